@@ -0,0 +1,299 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{serde_git, Error, Result};
+#[allow(unused)]
+use tracing::{debug, error, info, instrument, span, warn};
+
+/// A local branch
+#[derive(Debug, Clone)]
+pub struct Branch {
+    name: String,
+    target: Option<git2::Oid>,
+    upstream: Option<String>,
+    head: bool,
+}
+
+impl Branch {
+    /// Every local branch, sorted by name.
+    pub(crate) fn list(git: &git2::Repository) -> Result<Vec<Self>> {
+        let mut branches = vec![];
+        for branch in git.branches(Some(git2::BranchType::Local))? {
+            let (branch, _) = branch?;
+            let name = if let Some(name) = branch.name()? {
+                name.to_owned()
+            } else {
+                warn!("Skipping branch with a name that isn't UTF-8");
+                continue;
+            };
+            let upstream = match branch.upstream() {
+                Ok(upstream) => upstream.name()?.map(str::to_owned),
+                Err(err) if err.code() == git2::ErrorCode::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            branches.push(Self {
+                name,
+                target: branch.get().target(),
+                upstream,
+                head: branch.is_head(),
+            });
+        }
+        branches.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(branches)
+    }
+
+    /// The name without `refs/heads/`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The commit the branch points to
+    pub fn target(&self) -> Option<git2::Oid> {
+        self.target
+    }
+
+    /// The short name of the branch this one tracks, such as `origin/main`
+    pub fn upstream(&self) -> Option<&str> {
+        self.upstream.as_deref()
+    }
+
+    /// If this is the branch HEAD points to
+    pub fn is_head(&self) -> bool {
+        self.head
+    }
+}
+
+/// The full name of the reference for the branch `name`, checking it's a name
+/// git allows.
+pub(crate) fn ref_name(name: &str) -> Result<String> {
+    let full = format!("refs/heads/{}", name);
+    if git2::Reference::is_valid_name(&full) {
+        Ok(full)
+    } else {
+        Err(Error::InvalidBranchName(name.to_owned()))
+    }
+}
+
+fn find<'r>(git: &'r git2::Repository, name: &str) -> Result<git2::Branch<'r>> {
+    match git.find_branch(name, git2::BranchType::Local) {
+        Ok(branch) => Ok(branch),
+        Err(err) if err.code() == git2::ErrorCode::NotFound => {
+            Err(Error::BranchNotFound(name.to_owned()))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// A branch being renamed, which moves its reflog and config along with it
+/// and updates HEAD if it's checked out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Rename {
+    from: String,
+    to: String,
+}
+
+impl Rename {
+    pub(crate) fn record(git: &git2::Repository, from: &str, to: &str) -> Result<Self> {
+        let rename = Self {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        };
+        rename.apply(git)?;
+        Ok(rename)
+    }
+
+    pub(crate) fn apply(&self, git: &git2::Repository) -> Result<()> {
+        rename(git, &self.from, &self.to)
+    }
+
+    pub(crate) fn revert(&self, git: &git2::Repository) -> Result<()> {
+        rename(git, &self.to, &self.from)
+    }
+}
+
+fn rename(git: &git2::Repository, from: &str, to: &str) -> Result<()> {
+    ref_name(to)?;
+    let mut branch = find(git, from)?;
+    match branch.rename(to, false) {
+        Ok(_) => Ok(()),
+        Err(err) if err.code() == git2::ErrorCode::Exists => {
+            Err(Error::BranchExists(to.to_owned()))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// A branch being deleted along with its config, such as the upstream it
+/// tracks, so recreating it later doesn't leave that behind and undoing brings
+/// it all back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Delete {
+    name: String,
+    #[serde(with = "serde_git::oid")]
+    target: git2::Oid,
+    /// Each `branch.<name>.*` variable and its value, in the order git lists
+    /// them
+    config: Vec<(String, String)>,
+}
+
+impl Delete {
+    /// Delete the branch `name`, refusing if it's checked out.
+    pub(crate) fn record(git: &git2::Repository, name: &str) -> Result<Self> {
+        let branch = find(git, name)?;
+        if branch.is_head() {
+            return Err(Error::DeleteCurrentBranch(name.to_owned()));
+        }
+        let target = branch.get().peel_to_commit()?.id();
+
+        let prefix = format!("branch.{}.", name);
+        let mut config = vec![];
+        let entries = git.config()?.snapshot()?;
+        for entry in &entries.entries(None)? {
+            let entry = entry?;
+            if let (Some(var), Some(value)) = (entry.name(), entry.value()) {
+                if var.starts_with(&prefix) {
+                    config.push((var.to_owned(), value.to_owned()));
+                }
+            }
+        }
+
+        let delete = Self {
+            name: name.to_owned(),
+            target,
+            config,
+        };
+        delete.apply(git)?;
+        Ok(delete)
+    }
+
+    pub(crate) fn apply(&self, git: &git2::Repository) -> Result<()> {
+        // Deleting the branch removes its config section too, but fails on a
+        // variable with several values, so clear those out first
+        let mut config = git.config()?;
+        for (var, _) in &self.config {
+            match config.remove_multivar(var, ".*") {
+                Ok(()) => (),
+                // Already removed along with an earlier value
+                Err(err) if err.code() == git2::ErrorCode::NotFound => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        find(git, &self.name)?.delete()?;
+        Ok(())
+    }
+
+    pub(crate) fn revert(&self, git: &git2::Repository) -> Result<()> {
+        git.reference(
+            &ref_name(&self.name)?,
+            self.target,
+            false,
+            "idgit: undo branch: deleted",
+        )?;
+        let mut config = git.config()?;
+        for (var, value) in &self.config {
+            // A pattern that can't match any existing value adds another rather
+            // than replacing, which keeps variables with several values
+            config.set_multivar(var, "a^", value)?;
+        }
+        Ok(())
+    }
+}
+
+/// What HEAD points to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum Head {
+    Branch(String),
+    Detached(#[serde(with = "serde_git::oid")] git2::Oid),
+}
+
+impl Head {
+    fn current(git: &git2::Repository) -> Result<Self> {
+        let head = match git.head() {
+            Ok(head) => head,
+            Err(err) if err.code() == git2::ErrorCode::UnbornBranch => {
+                return Err(Error::UnbornHead)
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if git.head_detached()? {
+            let id = head.target().ok_or(Error::UnbornHead)?;
+            Ok(Self::Detached(id))
+        } else {
+            match head.shorthand() {
+                Some(name) => Ok(Self::Branch(name.to_owned())),
+                None => Err(Error::InvalidBranchName(
+                    String::from_utf8_lossy(head.shorthand_bytes()).into_owned(),
+                )),
+            }
+        }
+    }
+}
+
+/// HEAD being moved from one branch or commit to another, updating the index
+/// and workdir to match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Checkout {
+    before: Head,
+    after: Head,
+}
+
+impl Checkout {
+    /// Check out the branch `name`, refusing to overwrite uncommitted
+    /// changes.
+    pub(crate) fn record(git: &git2::Repository, name: &str) -> Result<Self> {
+        let checkout = Self {
+            before: Head::current(git)?,
+            after: Head::Branch(name.to_owned()),
+        };
+        checkout.apply(git)?;
+        Ok(checkout)
+    }
+
+    pub(crate) fn apply(&self, git: &git2::Repository) -> Result<()> {
+        switch(git, &self.after)
+    }
+
+    pub(crate) fn revert(&self, git: &git2::Repository) -> Result<()> {
+        switch(git, &self.before)
+    }
+}
+
+fn switch(git: &git2::Repository, to: &Head) -> Result<()> {
+    let commit = match to {
+        Head::Branch(name) => find(git, name)?.get().peel_to_commit()?,
+        Head::Detached(id) => git.find_commit(*id)?,
+    };
+    checkout_tree(git, &commit.tree()?)?;
+
+    match to {
+        Head::Branch(name) => git.set_head(&ref_name(name)?)?,
+        Head::Detached(id) => git.set_head_detached(*id)?,
+    }
+    Ok(())
+}
+
+/// Make the index and workdir match `tree`, failing without changing anything
+/// if that would overwrite uncommitted changes.
+fn checkout_tree(git: &git2::Repository, tree: &git2::Tree) -> Result<()> {
+    let mut conflicts: Vec<PathBuf> = vec![];
+    let result = {
+        let mut opts = git2::build::CheckoutBuilder::new();
+        opts.safe()
+            .notify_on(git2::CheckoutNotificationType::CONFLICT)
+            .notify(|_, path: Option<&Path>, _, _, _| {
+                if let Some(path) = path {
+                    conflicts.push(path.to_path_buf());
+                }
+                true
+            });
+        git.checkout_tree(tree.as_object(), Some(&mut opts))
+    };
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(_) if !conflicts.is_empty() => Err(Error::CheckoutConflicts(conflicts)),
+        Err(err) => Err(err.into()),
+    }
+}
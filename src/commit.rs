@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+#[allow(unused)]
+use tracing::{debug, error, info, instrument, span, warn};
+
+/// Options for [`crate::Repo::commit`], [`crate::Repo::amend`] and
+/// [`crate::Repo::reword`]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Options {
+    allow_empty: bool,
+    allow_merge: bool,
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a commit even if it has the same tree as its parent
+    pub fn allow_empty(&mut self, allow: bool) -> &mut Self {
+        self.allow_empty = allow;
+        self
+    }
+
+    /// Rewrite HEAD even if it is a merge commit
+    pub fn allow_merge(&mut self, allow: bool) -> &mut Self {
+        self.allow_merge = allow;
+        self
+    }
+
+    pub(crate) fn allows_empty(&self) -> bool {
+        self.allow_empty
+    }
+
+    pub(crate) fn allows_merge(&self) -> bool {
+        self.allow_merge
+    }
+}
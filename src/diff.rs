@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 use crate::RepoFile;
 
 #[allow(unused)]
@@ -52,6 +54,22 @@ impl Meta {
         }
     }
 
+    /// The file this delta is about. Where there are two this is the new one.
+    pub fn file(&self) -> &RepoFile {
+        match self {
+            Self::Added(f)
+            | Self::Deleted(f)
+            | Self::Modified { new: f, .. }
+            | Self::Renamed { new: f, .. }
+            | Self::Copied { new: f, .. }
+            | Self::Ignored(f)
+            | Self::Untracked(f)
+            | Self::Typechange { new: f, .. }
+            | Self::Unreadable(f)
+            | Self::Conflicted { new: f, .. } => f,
+        }
+    }
+
     fn get_new_file_only(from: &git2::DiffDelta) -> RepoFile {
         assert_eq!(from.nfiles(), 1);
         RepoFile::from_diff_file(&from.new_file())
@@ -71,27 +89,295 @@ impl Meta {
     }
 }
 
+/// Which half of the uncommitted changes to a file to look at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// From HEAD to the index
+    Staged,
+    /// From the index to the workdir
+    Unstaged,
+}
+
+/// One end of a [`crate::Repo::diff`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Revision {
+    /// Anything `git rev-parse` understands that names a commit, such as
+    /// `HEAD~2`, a branch, a tag or an id. If HEAD is unborn it's taken to be
+    /// empty.
+    Commit(String),
+    Index,
+    Workdir,
+}
+
+impl Revision {
+    pub fn commit(rev: &str) -> Self {
+        Self::Commit(rev.to_owned())
+    }
+}
+
+impl From<git2::Oid> for Revision {
+    fn from(id: git2::Oid) -> Self {
+        Self::Commit(id.to_string())
+    }
+}
+
+/// Options for [`crate::Repo::diff`]. The defaults match `git diff`.
+#[derive(Debug, Clone)]
+pub struct Options {
+    context_lines: u32,
+    renames: Option<u16>,
+    copies: Option<u16>,
+    ignore_whitespace: bool,
+    untracked: bool,
+    pathspec: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            context_lines: 3,
+            renames: Some(50),
+            copies: None,
+            ignore_whitespace: false,
+            untracked: false,
+            pathspec: vec![],
+        }
+    }
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many unchanged lines to show around each change
+    pub fn context_lines(&mut self, lines: u32) -> &mut Self {
+        self.context_lines = lines;
+        self
+    }
+
+    /// Detect renames of files at least `threshold` percent similar, or not at
+    /// all if `None`
+    pub fn detect_renames(&mut self, threshold: Option<u16>) -> &mut Self {
+        self.renames = threshold;
+        self
+    }
+
+    /// Detect copies of files at least `threshold` percent similar, or not at
+    /// all if `None`. Only modified files are considered as sources, as with
+    /// `git diff -C`.
+    pub fn detect_copies(&mut self, threshold: Option<u16>) -> &mut Self {
+        self.copies = threshold;
+        self
+    }
+
+    /// Leave out changes that only add or remove whitespace, like
+    /// `git diff -w`
+    pub fn ignore_whitespace(&mut self, ignore: bool) -> &mut Self {
+        self.ignore_whitespace = ignore;
+        self
+    }
+
+    /// When comparing with [`Revision::Workdir`], list untracked files as
+    /// well
+    pub fn include_untracked(&mut self, include: bool) -> &mut Self {
+        self.untracked = include;
+        self
+    }
+
+    /// Only compare files matching `spec`, as in `git diff -- <pathspec>`.
+    /// Can be given more than once to compare files matching any of them.
+    pub fn pathspec(&mut self, spec: &str) -> &mut Self {
+        self.pathspec.push(spec.to_owned());
+        self
+    }
+
+    pub(crate) fn to_git2(&self) -> git2::DiffOptions {
+        let mut opts = git2::DiffOptions::new();
+        opts.include_typechange(true)
+            .include_unmodified(false)
+            .include_unreadable(true)
+            .include_untracked(self.untracked)
+            .context_lines(self.context_lines)
+            .ignore_whitespace(self.ignore_whitespace);
+        for spec in &self.pathspec {
+            opts.pathspec(spec);
+        }
+        opts
+    }
+
+    /// How to find renames and copies, if at all
+    pub(crate) fn find_similar(&self) -> Option<git2::DiffFindOptions> {
+        if self.renames.is_none() && self.copies.is_none() {
+            return None;
+        }
+
+        let mut opts = git2::DiffFindOptions::new();
+        if let Some(threshold) = self.renames {
+            opts.renames(true).rename_threshold(threshold);
+        }
+        if let Some(threshold) = self.copies {
+            opts.copies(true).copy_threshold(threshold);
+        }
+        Some(opts)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Details {
     meta: Meta,
-    lines: Vec<Line>,
+    hunks: Vec<Hunk>,
+    side: Option<Side>,
 }
 
 impl Details {
-    pub(crate) fn new(meta: Meta, lines: Vec<Line>) -> Self {
-        Self { meta, lines }
+    pub(crate) fn new(meta: Meta, hunks: Vec<Hunk>) -> Self {
+        Self {
+            meta,
+            hunks,
+            side: None,
+        }
+    }
+
+    pub(crate) fn on_side(self, side: Side) -> Self {
+        Self {
+            side: Some(side),
+            ..self
+        }
+    }
+
+    pub fn meta(&self) -> &Meta {
+        &self.meta
+    }
+
+    /// Which half of the uncommitted changes these are, if they came from
+    /// [`crate::Repo::staged_details`] or [`crate::Repo::unstaged_details`]
+    pub fn side(&self) -> Option<Side> {
+        self.side
+    }
+
+    pub fn hunks(&self) -> &[Hunk] {
+        &self.hunks
+    }
+
+    /// Every line in every hunk, in order
+    pub fn lines(&self) -> impl Iterator<Item = &Line> {
+        self.hunks.iter().flat_map(Hunk::lines)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Hunk {
+    old_start: u32,
+    old_lines: u32,
+    new_start: u32,
+    new_lines: u32,
+    header: Vec<u8>,
+    lines: Vec<Line>,
+}
+
+impl Hunk {
+    pub(crate) fn from_git2(from: &git2::DiffHunk) -> Self {
+        Self {
+            old_start: from.old_start(),
+            old_lines: from.old_lines(),
+            new_start: from.new_start(),
+            new_lines: from.new_lines(),
+            header: from.header().to_vec(),
+            lines: vec![],
+        }
+    }
+
+    pub(crate) fn is_git2(&self, other: &git2::DiffHunk) -> bool {
+        self.old_start == other.old_start()
+            && self.old_lines == other.old_lines()
+            && self.new_start == other.new_start()
+            && self.new_lines == other.new_lines()
+    }
+
+    pub(crate) fn push(&mut self, line: Line) {
+        self.lines.push(line);
+    }
+
+    pub(crate) fn id(&self) -> HunkId {
+        HunkId {
+            old_start: self.old_start,
+            old_lines: self.old_lines,
+            new_start: self.new_start,
+            new_lines: self.new_lines,
+        }
+    }
+
+    /// Starting line number in the old file
+    pub fn old_start(&self) -> u32 {
+        self.old_start
+    }
+
+    /// Number of lines of the old file in this hunk
+    pub fn old_lines(&self) -> u32 {
+        self.old_lines
+    }
+
+    /// Starting line number in the new file
+    pub fn new_start(&self) -> u32 {
+        self.new_start
+    }
+
+    /// Number of lines of the new file in this hunk
+    pub fn new_lines(&self) -> u32 {
+        self.new_lines
+    }
+
+    /// Header text, such as `@@ -1,4 +1,4 @@ fn main() {`, including the
+    /// trailing newline
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+}
+
+/// Identifies a hunk by the lines of the old and new files it covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct HunkId {
+    old_start: u32,
+    old_lines: u32,
+    new_start: u32,
+    new_lines: u32,
+}
+
+/// Identifies a line in a diff by its position in the old and new files.
+/// Added lines have no old position and deleted lines have no new position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LineId {
+    old_lineno: Option<u32>,
+    new_lineno: Option<u32>,
+}
+
+impl LineId {
+    pub fn new(old_lineno: Option<u32>, new_lineno: Option<u32>) -> Self {
+        Self {
+            old_lineno,
+            new_lineno,
+        }
+    }
+
+    pub fn old_lineno(&self) -> Option<u32> {
+        self.old_lineno
+    }
+
+    pub fn new_lineno(&self) -> Option<u32> {
+        self.new_lineno
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Line {
-    /// Line number in old file or None for added line
     old_lineno: Option<u32>,
-    /// Line number in new file or None for deleted line
     new_lineno: Option<u32>,
-    /// Number of newline characters in content
     num_lines: u32,
-    /// Offset in the original file to the content
     content_offset: i64,
     content: Vec<u8>,
     origin: git2::DiffLineType,
@@ -108,4 +394,48 @@ impl Line {
             origin: from.origin_value(),
         }
     }
+
+    pub fn id(&self) -> LineId {
+        LineId::new(self.old_lineno, self.new_lineno)
+    }
+
+    /// Line number in old file or None for added line
+    pub fn old_lineno(&self) -> Option<u32> {
+        self.old_lineno
+    }
+
+    /// Line number in new file or None for deleted line
+    pub fn new_lineno(&self) -> Option<u32> {
+        self.new_lineno
+    }
+
+    /// Number of newline characters in content
+    pub fn num_lines(&self) -> u32 {
+        self.num_lines
+    }
+
+    /// Offset in the original file to the content
+    pub fn content_offset(&self) -> i64 {
+        self.content_offset
+    }
+
+    /// Content of the line, including the trailing newline if there is one
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    pub fn origin(&self) -> git2::DiffLineType {
+        self.origin
+    }
+
+    /// Whether this is a "\ No newline at end of file" marker rather than a
+    /// line of either file. The missing newline shows in the content of the
+    /// line before it instead, and the marker shares that line's numbers.
+    pub fn is_eofnl(&self) -> bool {
+        use git2::DiffLineType;
+        matches!(
+            self.origin,
+            DiffLineType::ContextEOFNL | DiffLineType::AddEOFNL | DiffLineType::DeleteEOFNL
+        )
+    }
 }
@@ -0,0 +1,175 @@
+use std::{
+    fmt, fs,
+    io::ErrorKind,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{serde_git, Result};
+#[allow(unused)]
+use tracing::{debug, error, info, instrument, span, warn};
+
+/// The parts of the repository's state a change depends on, so we can tell if
+/// something else modified the repository before we undo or redo it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Fingerprint {
+    #[serde(with = "serde_git::option_oid")]
+    head: Option<git2::Oid>,
+    #[serde(with = "serde_git::oid")]
+    index: git2::Oid,
+    paths: Vec<PathState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct PathState {
+    path: PathBuf,
+    /// Hash of the contents, or `None` if there is no file
+    #[serde(with = "serde_git::option_oid")]
+    content: Option<git2::Oid>,
+}
+
+impl Fingerprint {
+    /// Fingerprint HEAD, the index, and the workdir contents of `paths`.
+    pub(crate) fn take(
+        git: &git2::Repository,
+        index: &git2::Index,
+        workdir: &Path,
+        paths: &[&Path],
+    ) -> Result<Self> {
+        let head = match git.refname_to_id("HEAD") {
+            Ok(id) => Some(id),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut fingerprint = Self {
+            head,
+            index: Self::hash_index(index)?,
+            paths: vec![],
+        };
+        fingerprint.add_paths(workdir, paths)?;
+        Ok(fingerprint)
+    }
+
+    /// Also fingerprint the workdir contents of any of `paths` not already
+    /// included, leaving those that are as they were.
+    pub(crate) fn add_paths(&mut self, workdir: &Path, paths: &[&Path]) -> Result<()> {
+        for path in paths {
+            if !self.paths.iter().any(|state| state.path == *path) {
+                self.paths.push(PathState {
+                    path: path.to_path_buf(),
+                    content: Self::hash_file(&workdir.join(path))?,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// How `self` differs from what we `expected`, if at all.
+    pub(crate) fn diverged_from(&self, expected: &Self) -> Option<Divergence> {
+        let divergence = Divergence {
+            head: if self.head == expected.head {
+                None
+            } else {
+                Some((expected.head, self.head))
+            },
+            index: self.index != expected.index,
+            paths: self
+                .paths
+                .iter()
+                .filter(|state| !expected.paths.contains(state))
+                .map(|state| state.path.clone())
+                .collect(),
+        };
+
+        if divergence.head.is_none() && !divergence.index && divergence.paths.is_empty() {
+            None
+        } else {
+            Some(divergence)
+        }
+    }
+
+    /// Hash what's staged, ignoring stat information. Git updates that when
+    /// it refreshes the index, which doesn't change anything we care about.
+    fn hash_index(index: &git2::Index) -> Result<git2::Oid> {
+        let mut buf = vec![];
+        for entry in index.iter() {
+            buf.extend_from_slice(&entry.path);
+            buf.push(0);
+            buf.extend_from_slice(&entry.mode.to_be_bytes());
+            buf.extend_from_slice(&entry.flags.to_be_bytes());
+            buf.extend_from_slice(entry.id.as_bytes());
+        }
+        Ok(git2::Oid::hash_object(git2::ObjectType::Blob, &buf)?)
+    }
+
+    fn hash_file(abs: &Path) -> Result<Option<git2::Oid>> {
+        let meta = match fs::symlink_metadata(abs) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let content = if meta.file_type().is_symlink() {
+            fs::read_link(abs)?.as_os_str().as_bytes().to_vec()
+        } else {
+            fs::read(abs)?
+        };
+        Ok(Some(git2::Oid::hash_object(
+            git2::ObjectType::Blob,
+            &content,
+        )?))
+    }
+}
+
+/// What changed in the repository outside of idgit
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    head: Option<(Option<git2::Oid>, Option<git2::Oid>)>,
+    index: bool,
+    paths: Vec<PathBuf>,
+}
+
+impl Divergence {
+    /// Where HEAD was expected to be and where it is, if it moved. `None`
+    /// means it was unborn.
+    pub fn head_moved(&self) -> Option<(Option<git2::Oid>, Option<git2::Oid>)> {
+        self.head
+    }
+
+    pub fn index_changed(&self) -> bool {
+        self.index
+    }
+
+    /// Files in the workdir that changed
+    pub fn changed_paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn commit(id: Option<git2::Oid>) -> String {
+            id.map_or_else(|| "nothing".to_owned(), |id| id.to_string())
+        }
+
+        let mut changes = vec![];
+        if let Some((expected, actual)) = self.head {
+            changes.push(format!(
+                "HEAD moved from {} to {}",
+                commit(expected),
+                commit(actual)
+            ));
+        }
+        if self.index {
+            changes.push("the index changed".to_owned());
+        }
+        for path in &self.paths {
+            changes.push(format!("{} changed", path.display()));
+        }
+
+        write!(f, "{}", changes.join(", "))
+    }
+}
@@ -0,0 +1,171 @@
+use std::iter::FromIterator;
+
+use crate::LogCommit;
+#[allow(unused)]
+use tracing::{debug, error, info, instrument, span, warn};
+
+/// Where to draw each commit and the lines between them to show the commits
+/// listed by [`crate::Repo::log`] as a graph, like `git log --graph`.
+///
+/// Commits are added newest first, in an order where children come before
+/// their parents, such as [`crate::LogOrder::Topological`]. Each lane is a
+/// column waiting for a particular commit to be added. A lane is given up as
+/// soon as the commit it's waiting for is also waited for by another, and
+/// free lanes are reused before new ones are opened, so the graph is only as
+/// wide as the number of lines of history that are open at once.
+///
+/// Lanes waiting for commits that are never added, such as when the log is
+/// filtered or only partly read, stay open to the last row.
+#[derive(Debug, Clone, Default)]
+pub struct Graph {
+    /// The commit each lane is waiting for, `None` if it's free
+    lanes: Vec<Option<git2::Oid>>,
+    rows: Vec<Row>,
+}
+
+/// How to draw one commit in a [`Graph`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    id: git2::Oid,
+    column: usize,
+    through: Vec<usize>,
+    merged: Vec<usize>,
+    parents: Vec<usize>,
+    width: usize,
+}
+
+impl Row {
+    pub fn id(&self) -> git2::Oid {
+        self.id
+    }
+
+    /// The lane the commit is drawn in
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// Lanes that carry on past the commit, from the row above to the row
+    /// below
+    pub fn through(&self) -> &[usize] {
+        &self.through
+    }
+
+    /// Lanes other than [`Row::column`] that come from the row above and end
+    /// at the commit, as it's the parent of the commits drawn in them
+    pub fn merged(&self) -> &[usize] {
+        &self.merged
+    }
+
+    /// The lane each parent carries on in to the row below, in the same
+    /// order as [`LogCommit::parents`]. A lane that's also in
+    /// [`Row::through`] was already waiting for that parent.
+    pub fn parents(&self) -> &[usize] {
+        &self.parents
+    }
+
+    /// How many lanes to draw for this row
+    pub fn width(&self) -> usize {
+        self.width
+    }
+}
+
+impl Graph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the commit `id` as the next row.
+    pub fn push(&mut self, id: git2::Oid, parents: &[git2::Oid]) -> &Row {
+        let waiting = self.waiting_for(id);
+        for lane in &waiting {
+            self.lanes[*lane] = None;
+        }
+        let (column, merged) = match waiting.split_first() {
+            Some((column, merged)) => (*column, merged.to_vec()),
+            // Nothing added so far has this as a parent
+            None => (self.free_lane(&[]), vec![]),
+        };
+        let through = self.open_lanes();
+
+        let mut parent_lanes = Vec::with_capacity(parents.len());
+        for (n, parent) in parents.iter().enumerate() {
+            let lane = match self.waiting_for(*parent).first() {
+                Some(lane) => *lane,
+                None if n == 0 => column,
+                // Don't start a parent in a lane that's ending on this row
+                None => self.free_lane(&merged),
+            };
+            self.lanes[lane] = Some(*parent);
+            parent_lanes.push(lane);
+        }
+
+        while self.lanes.last() == Some(&None) {
+            self.lanes.pop();
+        }
+
+        let width = through
+            .iter()
+            .chain(&merged)
+            .chain(&parent_lanes)
+            .fold(column, |widest, lane| widest.max(*lane))
+            + 1;
+        self.rows.push(Row {
+            id,
+            column,
+            through,
+            merged,
+            parents: parent_lanes,
+            width,
+        });
+        &self.rows[self.rows.len() - 1]
+    }
+
+    /// Every row, in the order the commits were added
+    pub fn rows(&self) -> &[Row] {
+        &self.rows
+    }
+
+    /// The widest any row is
+    pub fn width(&self) -> usize {
+        self.rows.iter().map(Row::width).max().unwrap_or(0)
+    }
+
+    fn waiting_for(&self, id: git2::Oid) -> Vec<usize> {
+        self.lanes
+            .iter()
+            .enumerate()
+            .filter(|(_, waiting)| **waiting == Some(id))
+            .map(|(lane, _)| lane)
+            .collect()
+    }
+
+    fn open_lanes(&self) -> Vec<usize> {
+        self.lanes
+            .iter()
+            .enumerate()
+            .filter(|(_, waiting)| waiting.is_some())
+            .map(|(lane, _)| lane)
+            .collect()
+    }
+
+    /// The leftmost free lane other than those in `avoid`, opening a new one
+    /// if there isn't one
+    fn free_lane(&mut self, avoid: &[usize]) -> usize {
+        let lanes = &self.lanes;
+        let free = (0..lanes.len()).find(|lane| lanes[*lane].is_none() && !avoid.contains(lane));
+        free.unwrap_or_else(|| {
+            self.lanes.push(None);
+            self.lanes.len() - 1
+        })
+    }
+}
+
+impl<'c> FromIterator<&'c LogCommit> for Graph {
+    fn from_iter<I: IntoIterator<Item = &'c LogCommit>>(commits: I) -> Self {
+        let mut graph = Self::new();
+        for commit in commits {
+            graph.push(commit.id(), commit.parents());
+        }
+        graph
+    }
+}
@@ -0,0 +1,487 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::Result;
+#[allow(unused)]
+use tracing::{debug, error, info, instrument, span, warn};
+
+/// Bump whenever the serialized form of the history or anything in it changes
+/// incompatibly.
+const FORMAT_VERSION: u32 = 8;
+
+/// Something that can be done to a target and undone again, as kept in a
+/// [`History`].
+pub(crate) trait Action {
+    type Target;
+
+    fn apply(&mut self, target: &mut Self::Target) -> Result<()>;
+
+    fn undo(&mut self, target: &mut Self::Target) -> Result<()>;
+
+    /// Do it again after undoing it. By default the same as applying it.
+    fn redo(&mut self, target: &mut Self::Target) -> Result<()> {
+        self.apply(target)
+    }
+}
+
+/// An undo history that can be saved to disk and loaded again later.
+///
+/// Doing something new after undoing doesn't throw away what could have been
+/// redone, it starts a new branch from that point instead. So the history is a
+/// tree of branches, each forking from a point in an earlier one. Branch `0` is
+/// the root and ids never change, as branches are never removed.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct History<A> {
+    branches: Vec<Segment<A>>,
+    /// The branch undo and redo move along, from the root to its end
+    branch: usize,
+    /// Number of entries along the path to `branch` currently applied
+    depth: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Segment<A> {
+    /// Where this branch starts, `None` for the root
+    parent: Option<Fork>,
+    entries: Vec<Item<A>>,
+}
+
+/// A point in a branch, after the first `position` of its entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Fork {
+    branch: usize,
+    position: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Item<A> {
+    action: A,
+    timestamp: SystemTime,
+}
+
+/// A change in the history, as shown to the user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    description: String,
+    timestamp: SystemTime,
+    branch: usize,
+    position: usize,
+    is_current: bool,
+    is_applied: bool,
+}
+
+impl Entry {
+    /// What the change did, such as "Staged src/lib.rs"
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// When the change was first done
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// The branch this change is in
+    pub fn branch(&self) -> usize {
+        self.branch
+    }
+
+    /// Position in its branch just after this change, so that
+    /// `repo.goto(entry.branch(), entry.position())` goes back to it.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// If this is the last change applied, the one undo would undo
+    pub fn is_current(&self) -> bool {
+        self.is_current
+    }
+
+    /// If this change is currently applied
+    pub fn is_applied(&self) -> bool {
+        self.is_applied
+    }
+}
+
+/// A line of changes in the history, as shown to the user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Branch {
+    id: usize,
+    parent: Option<(usize, usize)>,
+    entries: Vec<Entry>,
+    is_current: bool,
+}
+
+impl Branch {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The branch and position in it this branch starts from, or `None` for
+    /// the root
+    pub fn parent(&self) -> Option<(usize, usize)> {
+        self.parent
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// If this is the branch undo and redo move along
+    pub fn is_current(&self) -> bool {
+        self.is_current
+    }
+}
+
+impl<A: Action> History<A> {
+    pub(crate) fn new() -> Self {
+        Self {
+            branches: vec![Segment {
+                parent: None,
+                entries: vec![],
+            }],
+            branch: 0,
+            depth: 0,
+        }
+    }
+
+    /// Number of entries along the path to the current branch currently
+    /// applied
+    pub(crate) fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Every entry along the path to the current branch
+    pub(crate) fn entries(&self) -> Vec<Entry>
+    where
+        A: fmt::Display,
+    {
+        (1..=self.path_len(self.branch))
+            .map(|depth| {
+                let at = self.locate(self.branch, depth);
+                self.entry(at.branch, at.position - 1)
+            })
+            .collect()
+    }
+
+    pub(crate) fn branches(&self) -> Vec<Branch>
+    where
+        A: fmt::Display,
+    {
+        self.branches
+            .iter()
+            .enumerate()
+            .map(|(id, segment)| Branch {
+                id,
+                parent: segment.parent.map(|fork| (fork.branch, fork.position)),
+                entries: (0..segment.entries.len())
+                    .map(|index| self.entry(id, index))
+                    .collect(),
+                is_current: id == self.branch,
+            })
+            .collect()
+    }
+
+    pub(crate) fn can_undo(&self) -> bool {
+        self.depth > 0
+    }
+
+    pub(crate) fn can_redo(&self) -> bool {
+        self.depth < self.path_len(self.branch)
+    }
+
+    /// The action [`History::undo`] would undo
+    pub(crate) fn next_undo(&self) -> Option<&A> {
+        if self.can_undo() {
+            Some(&self.item(self.depth).action)
+        } else {
+            None
+        }
+    }
+
+    /// The action [`History::redo`] would redo
+    pub(crate) fn next_redo(&self) -> Option<&A> {
+        if self.can_redo() {
+            Some(&self.item(self.depth + 1).action)
+        } else {
+            None
+        }
+    }
+
+    /// Apply `action` and push it. If anything could have been redone the
+    /// action starts a new branch, leaving that where it was.
+    pub(crate) fn apply(&mut self, target: &mut A::Target, mut action: A) -> Result<()> {
+        action.apply(target)?;
+        self.push(action);
+        Ok(())
+    }
+
+    /// Push an action that has already been applied, as [`History::apply`]
+    /// does.
+    pub(crate) fn push(&mut self, action: A) {
+        let item = Item {
+            action,
+            timestamp: SystemTime::now(),
+        };
+
+        if self.can_redo() {
+            let parent = self.locate(self.branch, self.depth);
+            self.branches.push(Segment {
+                parent: Some(parent),
+                entries: vec![item],
+            });
+            self.branch = self.branches.len() - 1;
+        } else {
+            self.branches[self.branch].entries.push(item);
+        }
+        self.depth += 1;
+    }
+
+    pub(crate) fn undo(&mut self, target: &mut A::Target) -> Option<Result<()>> {
+        if !self.can_undo() {
+            return None;
+        }
+
+        let output = self.item_mut(self.depth).action.undo(target);
+        if output.is_ok() {
+            self.depth -= 1;
+        }
+        Some(output)
+    }
+
+    pub(crate) fn redo(&mut self, target: &mut A::Target) -> Option<Result<()>> {
+        if !self.can_redo() {
+            return None;
+        }
+
+        let output = self.item_mut(self.depth + 1).action.redo(target);
+        if output.is_ok() {
+            self.depth += 1;
+        }
+        Some(output)
+    }
+
+    /// How to get to `position` in `branch`: the depth to undo back to, where
+    /// the path there meets the current one, and the depth to then redo to
+    /// along the path to `branch`. `None` if there is no such point.
+    pub(crate) fn route(&self, branch: usize, position: usize) -> Option<(usize, usize)> {
+        let segment = self.branches.get(branch)?;
+        if position > segment.entries.len() {
+            return None;
+        }
+
+        let target = self.start(branch) + position;
+        let mut common = self.depth.min(target);
+        while common > 0 && self.locate(self.branch, common) != self.locate(branch, common) {
+            common -= 1;
+        }
+        Some((common, target))
+    }
+
+    /// Move onto `branch` without applying or undoing anything. The current
+    /// depth must be on the path to it, as found by [`History::route`].
+    pub(crate) fn switch(&mut self, branch: usize) {
+        debug_assert!(self.depth <= self.start(branch) + self.branches[branch].entries.len());
+        debug_assert!(
+            self.depth == 0
+                || self.locate(self.branch, self.depth) == self.locate(branch, self.depth)
+        );
+        self.branch = branch;
+    }
+
+    /// Depth along the path to `branch` where it forks from its parent
+    fn start(&self, branch: usize) -> usize {
+        match self.branches[branch].parent {
+            Some(fork) => self.start(fork.branch) + fork.position,
+            None => 0,
+        }
+    }
+
+    /// Number of entries along the path to `branch`
+    fn path_len(&self, branch: usize) -> usize {
+        self.start(branch) + self.branches[branch].entries.len()
+    }
+
+    /// The branch and position in it that `depth` along the path to `branch`
+    /// is, taking the earliest branch it's in.
+    fn locate(&self, mut branch: usize, depth: usize) -> Fork {
+        loop {
+            let start = self.start(branch);
+            match self.branches[branch].parent {
+                Some(fork) if depth <= start => branch = fork.branch,
+                _ => {
+                    return Fork {
+                        branch,
+                        position: depth - start,
+                    }
+                }
+            }
+        }
+    }
+
+    /// The item at `depth` along the path to the current branch
+    fn item(&self, depth: usize) -> &Item<A> {
+        let at = self.locate(self.branch, depth);
+        &self.branches[at.branch].entries[at.position - 1]
+    }
+
+    fn item_mut(&mut self, depth: usize) -> &mut Item<A> {
+        let at = self.locate(self.branch, depth);
+        &mut self.branches[at.branch].entries[at.position - 1]
+    }
+
+    fn entry(&self, branch: usize, index: usize) -> Entry
+    where
+        A: fmt::Display,
+    {
+        let item = &self.branches[branch].entries[index];
+        let at = Fork {
+            branch,
+            position: index + 1,
+        };
+        let depth = self.start(branch) + at.position;
+        let is_applied = depth <= self.depth && self.locate(self.branch, depth) == at;
+
+        Entry {
+            description: item.action.to_string(),
+            timestamp: item.timestamp,
+            branch,
+            position: at.position,
+            is_current: is_applied && depth == self.depth,
+            is_applied,
+        }
+    }
+
+    /// If every fork points to a real earlier point, so none of the above can
+    /// panic or loop forever.
+    fn is_consistent(&self) -> bool {
+        let forks_valid =
+            self.branches
+                .iter()
+                .enumerate()
+                .all(|(id, segment)| match segment.parent {
+                    None => id == 0,
+                    Some(fork) => {
+                        fork.branch < id
+                            && fork.position <= self.branches[fork.branch].entries.len()
+                    }
+                });
+
+        forks_valid
+            && !self.branches.is_empty()
+            && self.branch < self.branches.len()
+            && self.depth <= self.path_len(self.branch)
+    }
+}
+
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+#[derive(Serialize)]
+struct SaveFile<'a, A> {
+    version: u32,
+    history: &'a History<A>,
+}
+
+#[derive(Deserialize)]
+struct LoadFile<A> {
+    history: History<A>,
+}
+
+impl<A> History<A>
+where
+    A: Action + Serialize + DeserializeOwned,
+{
+    /// Load the history saved at `path`.
+    ///
+    /// If there isn't one we start afresh. If it can't be read or was written
+    /// by an incompatible version it is moved aside so nothing is lost and we
+    /// also start afresh, as being unable to undo is better than being unable
+    /// to open the repo.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        match serde_json::from_slice::<Version>(&contents) {
+            Ok(Version { version }) if version == FORMAT_VERSION => (),
+            Ok(Version { version }) => {
+                warn!(
+                    "History at {:?} has unsupported format version {}",
+                    path, version
+                );
+                Self::move_aside(path, &format!("v{}", version));
+                return Ok(Self::new());
+            }
+            Err(err) => {
+                warn!("Failed to read history at {:?}: {}", path, err);
+                Self::move_aside(path, "corrupt");
+                return Ok(Self::new());
+            }
+        }
+
+        match serde_json::from_slice::<LoadFile<A>>(&contents) {
+            Ok(LoadFile { history }) if history.is_consistent() => Ok(history),
+            Ok(_) => {
+                warn!("History at {:?} is inconsistent", path);
+                Self::move_aside(path, "corrupt");
+                Ok(Self::new())
+            }
+            Err(err) => {
+                warn!("Failed to read history at {:?}: {}", path, err);
+                Self::move_aside(path, "corrupt");
+                Ok(Self::new())
+            }
+        }
+    }
+
+    /// Save to `path`, replacing whatever is there atomically.
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = SaveFile {
+            version: FORMAT_VERSION,
+            history: self,
+        };
+        let contents = serde_json::to_vec(&file).map_err(io::Error::from)?;
+
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Rename the file at `path` by adding `suffix`, and a number if that's
+    /// taken by an earlier one. If it can't be moved it's left to be
+    /// overwritten by the next save.
+    fn move_aside(path: &Path, suffix: &str) {
+        let named = |suffix: &str| {
+            let mut aside = path.as_os_str().to_owned();
+            aside.push(".");
+            aside.push(suffix);
+            PathBuf::from(aside)
+        };
+        let mut aside = named(suffix);
+        let mut n = 0;
+        while aside.exists() {
+            n += 1;
+            aside = named(&format!("{}.{}", suffix, n));
+        }
+
+        match fs::rename(path, &aside) {
+            Ok(()) => warn!("Moved history at {:?} to {:?}", path, aside),
+            Err(err) => warn!("Failed to move history at {:?} aside: {}", path, err),
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{serde_git, Result};
+#[allow(unused)]
+use tracing::{debug, error, info, instrument, span, warn};
+
+/// Owned copy of a [`git2::IndexEntry`] that can be written back exactly,
+/// including its stat information and flags.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    ctime: (i32, u32),
+    mtime: (i32, u32),
+    dev: u32,
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    file_size: u32,
+    #[serde(with = "serde_git::oid")]
+    id: git2::Oid,
+    flags: u16,
+    flags_extended: u16,
+    path: Vec<u8>,
+}
+
+impl Entry {
+    /// A new entry for a blob with no stat information, so that git re-checks
+    /// the workdir file rather than trusting the entry.
+    pub(crate) fn new_blob(path: &Path, id: git2::Oid, mode: u32, size: usize) -> Self {
+        Self {
+            ctime: (0, 0),
+            mtime: (0, 0),
+            dev: 0,
+            ino: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            file_size: truncate!(size, u32),
+            id,
+            flags: 0,
+            flags_extended: 0,
+            path: path.as_os_str().as_bytes().to_vec(),
+        }
+    }
+
+    /// Get the stage 0 entry for `path`, if any.
+    pub(crate) fn get(index: &git2::Index, path: &Path) -> Option<Self> {
+        index.get_path(path, 0).map(Self::from)
+    }
+
+    /// Make the stage 0 entry for `path` exactly `entry`, or remove it if
+    /// `None`, and write the index.
+    pub(crate) fn set(index: &mut git2::Index, path: &Path, entry: Option<&Self>) -> Result<()> {
+        match entry {
+            Some(entry) => index.add(&entry.to_git2())?,
+            None => index.remove_path(path)?,
+        }
+        index.write()?;
+        Ok(())
+    }
+
+    /// This entry pointing at a different blob. Stat information is cleared
+    /// as it no longer describes the contents.
+    pub(crate) fn with_blob(self, id: git2::Oid, size: usize) -> Self {
+        Self {
+            ctime: (0, 0),
+            mtime: (0, 0),
+            file_size: truncate!(size, u32),
+            id,
+            ..self
+        }
+    }
+
+    /// This entry with a different mode. Stat information is kept, as only
+    /// git's view of the file changed.
+    pub(crate) fn with_mode(self, mode: u32) -> Self {
+        Self { mode, ..self }
+    }
+
+    pub(crate) fn id(&self) -> git2::Oid {
+        self.id
+    }
+
+    pub(crate) fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    pub(crate) fn to_git2(&self) -> git2::IndexEntry {
+        git2::IndexEntry {
+            ctime: git2::IndexTime::new(self.ctime.0, self.ctime.1),
+            mtime: git2::IndexTime::new(self.mtime.0, self.mtime.1),
+            dev: self.dev,
+            ino: self.ino,
+            mode: self.mode,
+            uid: self.uid,
+            gid: self.gid,
+            file_size: self.file_size,
+            id: self.id,
+            flags: self.flags,
+            flags_extended: self.flags_extended,
+            path: self.path.clone(),
+        }
+    }
+}
+
+impl From<git2::IndexEntry> for Entry {
+    fn from(from: git2::IndexEntry) -> Self {
+        Self {
+            ctime: (from.ctime.seconds(), from.ctime.nanoseconds()),
+            mtime: (from.mtime.seconds(), from.mtime.nanoseconds()),
+            dev: from.dev,
+            ino: from.ino,
+            mode: from.mode,
+            uid: from.uid,
+            gid: from.gid,
+            file_size: from.file_size,
+            id: from.id,
+            flags: from.flags,
+            flags_extended: from.flags_extended,
+            path: from.path,
+        }
+    }
+}
+
+/// The entry for a path before and after some change to the index, so the
+/// change can be undone and redone exactly.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Update {
+    path: PathBuf,
+    before: Option<Entry>,
+    after: Option<Entry>,
+}
+
+impl Update {
+    /// Run `change` and record what it did to the entry for `path`. The index
+    /// is written afterwards.
+    pub(crate) fn record<F>(index: &mut git2::Index, path: &Path, change: F) -> Result<Self>
+    where
+        F: FnOnce(&mut git2::Index) -> Result<()>,
+    {
+        let before = Entry::get(index, path);
+        change(index)?;
+        let after = Entry::get(index, path);
+        index.write()?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            before,
+            after,
+        })
+    }
+
+    pub(crate) fn apply(&self, index: &mut git2::Index) -> Result<()> {
+        Entry::set(index, &self.path, self.after.as_ref())
+    }
+
+    pub(crate) fn revert(&self, index: &mut git2::Index) -> Result<()> {
+        Entry::set(index, &self.path, self.before.as_ref())
+    }
+}
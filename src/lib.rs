@@ -12,13 +12,38 @@ macro_rules! truncate {
     }};
 }
 
-mod diff;
+mod branch;
+mod commit;
+pub mod diff;
 mod file;
+mod fingerprint;
+mod graph;
+mod history;
+mod index;
+mod log;
+mod patch;
+mod refs;
 mod repo;
+mod serde_git;
+mod stash;
+mod status;
+mod watch;
+mod workdir;
+mod worker;
 
-pub use diff::Meta;
+pub use branch::Branch;
+pub use commit::Options as CommitOptions;
+pub use diff::{Meta, Options as DiffOptions, Revision};
 pub use file::File as RepoFile;
-pub use repo::Repo;
+pub use fingerprint::Divergence;
+pub use graph::{Graph, Row as GraphRow};
+pub use history::{Branch as HistoryBranch, Entry as HistoryEntry};
+pub use log::{Commit as LogCommit, Log, Options as LogOptions, Order as LogOrder};
+pub use repo::{DiscardTo, Repo};
+pub use stash::{Scope as StashScope, Stash};
+pub use status::{Options as StatusOptions, Status, Untracked};
+pub use watch::Subscription;
+pub use worker::{Response as WorkerResponse, Worker};
 
 use std::{io, path::PathBuf};
 #[allow(unused)]
@@ -32,6 +57,8 @@ pub enum Error {
     Git2(#[from] git2::Error),
     /// IO Error
     Io(#[from] io::Error),
+    /// Error watching for changes: {0}
+    Watch(#[from] notify::Error),
     /// Path must be specified, got ({0:?})
     MissingPath(RepoFile),
     /// Id must be specified, got ({0:?})
@@ -42,6 +69,46 @@ pub enum Error {
     UndoEmpty,
     /// Can't redo from the current point
     RedoEmpty,
+    /// No position {1} in branch {0} of the history
+    HistoryPointNotFound(usize, usize),
+    /// Can't undo or redo in the middle of a transaction
+    UndoInTransaction,
+    /// Cancelled before finishing
+    Cancelled,
     /// Expected to find something at {0}
     PathNotFound(PathBuf),
+    /// No hunk matching the one given in {0}. Has the file changed?
+    HunkNotFound(PathBuf),
+    /// Expected details of the {1:?} changes to {0:?}
+    WrongSide(PathBuf, diff::Side),
+    /// No lines matching {1:?} in {0:?}. Has the file changed?
+    LinesNotFound(PathBuf, Vec<diff::LineId>),
+    /// No lines of {0:?} selected
+    NoLinesSelected(PathBuf),
+    /// Nothing to commit, the index is the same as HEAD
+    NothingToCommit,
+    /// HEAD doesn't point to a commit yet
+    UnbornHead,
+    /// HEAD ({0}) is a merge commit
+    HeadIsMerge(git2::Oid),
+    /// No commit matching {0}
+    RevisionNotFound(String),
+    /// {0} isn't a valid branch name
+    InvalidBranchName(String),
+    /// No branch named {0}
+    BranchNotFound(String),
+    /// A branch named {0} already exists
+    BranchExists(String),
+    /// Can't delete {0} as it's checked out
+    DeleteCurrentBranch(String),
+    /// Checking out would overwrite uncommitted changes to {0:?}
+    CheckoutConflicts(Vec<PathBuf>),
+    /// No stash at index {0}
+    StashNotFound(usize),
+    /// Nothing to stash
+    NothingToStash,
+    /// Changes to {0:?} conflict with the stash
+    StashConflicts(Vec<PathBuf>),
+    /// The repository was changed outside idgit since this was done: {0}
+    HistoryDiverged(Divergence),
 }
@@ -0,0 +1,334 @@
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::Result;
+#[allow(unused)]
+use tracing::{debug, error, info, instrument, span, warn};
+
+/// Options for [`crate::Repo::log`]. By default every commit reachable from
+/// HEAD is listed, newest first, like `git log`.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    from: Vec<String>,
+    all: bool,
+    order: Order,
+    paths: Vec<String>,
+    author: Option<String>,
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+    message: Option<String>,
+}
+
+/// The order commits are listed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    /// Newest first by commit date, though never a parent before its child,
+    /// like `git log --date-order`
+    Date,
+    /// Children always before their parents, like `git log --topo-order`
+    Topological,
+}
+
+impl Default for Order {
+    fn default() -> Self {
+        Self::Date
+    }
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from `rev` rather than HEAD. Can be given more than once to
+    /// list commits reachable from any of them.
+    pub fn from(&mut self, rev: &str) -> &mut Self {
+        self.from.push(rev.to_owned());
+        self
+    }
+
+    /// Start from every branch, tag and remote branch as well, like
+    /// `git log --branches --tags --remotes`
+    pub fn all(&mut self, all: bool) -> &mut Self {
+        self.all = all;
+        self
+    }
+
+    pub fn order(&mut self, order: Order) -> &mut Self {
+        self.order = order;
+        self
+    }
+
+    /// Only list commits that change files matching `spec`, as in
+    /// `git log -- <pathspec>`. Can be given more than once to list commits
+    /// that change any of them.
+    pub fn path(&mut self, spec: &str) -> &mut Self {
+        self.paths.push(spec.to_owned());
+        self
+    }
+
+    /// Only list commits whose author's name or email contains `author`
+    pub fn author(&mut self, author: &str) -> &mut Self {
+        self.author = Some(author.to_owned());
+        self
+    }
+
+    /// Only list commits committed at or after `time`
+    pub fn since(&mut self, time: SystemTime) -> &mut Self {
+        self.since = Some(time);
+        self
+    }
+
+    /// Only list commits committed at or before `time`
+    pub fn until(&mut self, time: SystemTime) -> &mut Self {
+        self.until = Some(time);
+        self
+    }
+
+    /// Only list commits whose message contains `text`
+    pub fn message(&mut self, text: &str) -> &mut Self {
+        self.message = Some(text.to_owned());
+        self
+    }
+}
+
+/// A commit as listed by [`crate::Repo::log`]
+#[derive(Debug, Clone)]
+pub struct Commit {
+    id: git2::Oid,
+    parents: Vec<git2::Oid>,
+    author_name: String,
+    author_email: String,
+    author_time: SystemTime,
+    commit_time: SystemTime,
+    summary: String,
+    refs: Vec<String>,
+}
+
+impl Commit {
+    pub fn id(&self) -> git2::Oid {
+        self.id
+    }
+
+    /// The first parent is the one the commit was made on, any others were
+    /// merged in
+    pub fn parents(&self) -> &[git2::Oid] {
+        &self.parents
+    }
+
+    pub fn author_name(&self) -> &str {
+        &self.author_name
+    }
+
+    pub fn author_email(&self) -> &str {
+        &self.author_email
+    }
+
+    /// When the commit was authored
+    pub fn author_time(&self) -> SystemTime {
+        self.author_time
+    }
+
+    /// When the commit was committed, which is what [`Options::since`] and
+    /// [`Options::until`] filter on
+    pub fn commit_time(&self) -> SystemTime {
+        self.commit_time
+    }
+
+    /// The first line of the message
+    pub fn summary(&self) -> &str {
+        &self.summary
+    }
+
+    /// Short names of the branches, tags and remote branches pointing at the
+    /// commit, and `HEAD` if it's checked out
+    pub fn refs(&self) -> &[String] {
+        &self.refs
+    }
+}
+
+/// The commits matching some [`Options`], found as they're asked for so
+/// only as much history as is needed is read. Page through it with
+/// [`Iterator::skip`] and [`Iterator::take`].
+pub struct Log<'r> {
+    git: &'r git2::Repository,
+    walk: git2::Revwalk<'r>,
+    options: Options,
+    refs: HashMap<git2::Oid, Vec<String>>,
+}
+
+impl<'r> Log<'r> {
+    pub(crate) fn new(git: &'r git2::Repository, options: &Options) -> Result<Self> {
+        let mut walk = git.revwalk()?;
+        walk.set_sorting(match options.order {
+            // Sorting by time alone can put a parent before a child committed
+            // in the same second
+            Order::Date => git2::Sort::TOPOLOGICAL | git2::Sort::TIME,
+            Order::Topological => git2::Sort::TOPOLOGICAL,
+        })?;
+
+        for rev in &options.from {
+            let id = git.revparse_single(rev)?.peel_to_commit()?.id();
+            walk.push(id)?;
+        }
+        if options.all {
+            walk.push_glob("refs/heads/*")?;
+            walk.push_glob("refs/tags/*")?;
+            walk.push_glob("refs/remotes/*")?;
+        }
+        if options.from.is_empty() {
+            // push_head gives a generic error when nothing has been committed
+            // yet, so look for that first
+            match git.head() {
+                Ok(_) => walk.push_head()?,
+                Err(err) if err.code() == git2::ErrorCode::UnbornBranch => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(Self {
+            git,
+            walk,
+            options: options.clone(),
+            refs: refs_by_target(git)?,
+        })
+    }
+
+    fn matches(&self, commit: &git2::Commit) -> Result<bool> {
+        let options = &self.options;
+
+        let committed = to_system_time(commit.committer().when());
+        if options.since.map_or(false, |since| committed < since)
+            || options.until.map_or(false, |until| committed > until)
+        {
+            return Ok(false);
+        }
+
+        if let Some(author) = &options.author {
+            let signature = commit.author();
+            let name = signature.name().unwrap_or_default();
+            let email = signature.email().unwrap_or_default();
+            if !name.contains(author.as_str()) && !email.contains(author.as_str()) {
+                return Ok(false);
+            }
+        }
+
+        if let Some(text) = &options.message {
+            if !commit.message().unwrap_or_default().contains(text.as_str()) {
+                return Ok(false);
+            }
+        }
+
+        if options.paths.is_empty() {
+            Ok(true)
+        } else {
+            self.touches_paths(commit)
+        }
+    }
+
+    /// If `commit` changes a file matching the paths we're looking for. Like
+    /// `git log`, a merge only counts if it differs from every parent, as
+    /// otherwise the change is listed where it was made.
+    fn touches_paths(&self, commit: &git2::Commit) -> Result<bool> {
+        let mut opts = git2::DiffOptions::new();
+        for spec in &self.options.paths {
+            opts.pathspec(spec);
+        }
+        let tree = commit.tree()?;
+
+        if commit.parent_count() == 0 {
+            let diff = self
+                .git
+                .diff_tree_to_tree(None, Some(&tree), Some(&mut opts))?;
+            return Ok(diff.deltas().len() > 0);
+        }
+        for parent in commit.parents() {
+            let diff =
+                self.git
+                    .diff_tree_to_tree(Some(&parent.tree()?), Some(&tree), Some(&mut opts))?;
+            if diff.deltas().len() == 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn summarize(&self, commit: &git2::Commit) -> Commit {
+        let author = commit.author();
+        Commit {
+            id: commit.id(),
+            parents: commit.parent_ids().collect(),
+            author_name: author.name().unwrap_or_default().to_owned(),
+            author_email: author.email().unwrap_or_default().to_owned(),
+            author_time: to_system_time(author.when()),
+            commit_time: to_system_time(commit.committer().when()),
+            summary: commit.summary().unwrap_or_default().to_owned(),
+            refs: self.refs.get(&commit.id()).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+impl Iterator for Log<'_> {
+    type Item = Result<Commit>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let id = match self.walk.next()? {
+                Ok(id) => id,
+                Err(err) => return Some(Err(err.into())),
+            };
+            let commit = match self.git.find_commit(id) {
+                Ok(commit) => commit,
+                Err(err) => return Some(Err(err.into())),
+            };
+            match self.matches(&commit) {
+                Ok(true) => return Some(Ok(self.summarize(&commit))),
+                // Keep walking until a commit matches
+                Ok(false) => (),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// Short names of the references pointing at each commit, with tags peeled
+/// to the commit they tag.
+fn refs_by_target(git: &git2::Repository) -> Result<HashMap<git2::Oid, Vec<String>>> {
+    let mut refs: HashMap<git2::Oid, Vec<String>> = HashMap::new();
+    for reference in git.references()? {
+        let reference = reference?;
+        // The stash isn't something anyone wants to see in a log
+        if reference.name() == Some("refs/stash") {
+            continue;
+        }
+        let name = match reference.shorthand() {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        if let Ok(commit) = reference.peel_to_commit() {
+            refs.entry(commit.id()).or_default().push(name);
+        }
+    }
+
+    for names in refs.values_mut() {
+        names.sort();
+    }
+    if let Ok(head) = git.head() {
+        if let Ok(commit) = head.peel_to_commit() {
+            refs.entry(commit.id())
+                .or_default()
+                .insert(0, "HEAD".to_owned());
+        }
+    }
+    Ok(refs)
+}
+
+fn to_system_time(time: git2::Time) -> SystemTime {
+    match u64::try_from(time.seconds()) {
+        Ok(seconds) => UNIX_EPOCH + Duration::from_secs(seconds),
+        Err(_) => UNIX_EPOCH - Duration::from_secs(time.seconds().unsigned_abs()),
+    }
+}
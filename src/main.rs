@@ -1,3 +1,26 @@
+#![warn(clippy::all, clippy::pedantic)]
+
+mod ui;
+
+use std::{env, path::PathBuf, process};
+
+use idgit::Repo;
+
 fn main() {
-    println!("Hello, world!");
+    let path = env::args_os()
+        .nth(1)
+        .map_or_else(|| PathBuf::from("."), PathBuf::from);
+
+    let repo = match Repo::open(&path) {
+        Ok(repo) => repo,
+        Err(err) => {
+            eprintln!("idgit: can't open {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    };
+
+    if let Err(err) = ui::run(repo) {
+        eprintln!("idgit: {}", err);
+        process::exit(1);
+    }
 }
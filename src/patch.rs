@@ -0,0 +1,88 @@
+use crate::diff::Line;
+
+#[allow(unused)]
+use tracing::{debug, error, info, instrument, span, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    /// Start from the old file and apply the selected lines
+    Forward,
+    /// Start from the new file and revert the selected lines
+    Reverse,
+}
+
+/// Synthesize the contents of a file by applying (or reverting) only the
+/// selected lines of a diff to `base`.
+///
+/// `base` must be the old file for [`Direction::Forward`] and the new file for
+/// [`Direction::Reverse`], and `lines` must be in the order git produced them.
+/// Unselected additions are dropped and unselected deletions are kept.
+///
+/// A line without a final newline is the last line of its file, so whether the
+/// result ends in a newline follows from which last line is taken.
+pub(crate) fn apply<'a, L, F>(
+    base: &[u8],
+    lines: L,
+    mut selected: F,
+    direction: Direction,
+) -> Vec<u8>
+where
+    L: IntoIterator<Item = &'a Line>,
+    F: FnMut(&Line) -> bool,
+{
+    let base: Vec<&[u8]> = base.split_inclusive(|&b| b == b'\n').collect();
+    let mut out = vec![];
+    // Number of lines of base already consumed
+    let mut consumed = 0;
+
+    for line in lines.into_iter().filter(|line| !line.is_eofnl()) {
+        let (ours, theirs) = match direction {
+            Direction::Forward => (line.old_lineno(), line.new_lineno()),
+            Direction::Reverse => (line.new_lineno(), line.old_lineno()),
+        };
+
+        match (ours, theirs) {
+            // Context
+            (Some(lineno), Some(_)) => {
+                let lineno = lineno as usize;
+                copy(&mut out, &base, consumed, lineno);
+                consumed = lineno;
+            }
+            // Only in base
+            (Some(lineno), None) => {
+                let lineno = lineno as usize;
+                copy(&mut out, &base, consumed, lineno - 1);
+                if !selected(line) {
+                    copy(&mut out, &base, lineno - 1, lineno);
+                }
+                consumed = lineno;
+            }
+            // Not in base
+            (None, Some(_)) => {
+                if selected(line) {
+                    push(&mut out, line.content());
+                }
+            }
+            // Not a line of either file
+            (None, None) => (),
+        }
+    }
+
+    copy(&mut out, &base, consumed, base.len());
+    out
+}
+
+fn copy(out: &mut Vec<u8>, base: &[&[u8]], from: usize, to: usize) {
+    for line in base.iter().take(to).skip(from) {
+        push(out, line);
+    }
+}
+
+fn push(out: &mut Vec<u8>, line: &[u8]) {
+    // Only the last line of a file can lack a newline. If we're adding after
+    // it it's no longer last.
+    if out.last().map_or(false, |&b| b != b'\n') {
+        out.push(b'\n');
+    }
+    out.extend_from_slice(line);
+}
@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{serde_git, Result};
+#[allow(unused)]
+use tracing::{debug, error, info, instrument, span, warn};
+
+/// The target of a reference before and after some change, so the change can
+/// be undone and redone exactly. A target of `None` means the reference
+/// doesn't exist, such as a branch that hasn't been born yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Update {
+    name: String,
+    #[serde(with = "serde_git::option_oid")]
+    before: Option<git2::Oid>,
+    #[serde(with = "serde_git::option_oid")]
+    after: Option<git2::Oid>,
+    message: String,
+}
+
+impl Update {
+    /// Point `name` at `after`, recording where it pointed before. `message`
+    /// is used for the reflog.
+    pub(crate) fn record(
+        git: &git2::Repository,
+        name: &str,
+        after: Option<git2::Oid>,
+        message: &str,
+    ) -> Result<Self> {
+        let before = target(git, name)?;
+        set(git, name, after, message)?;
+
+        Ok(Self {
+            name: name.to_owned(),
+            before,
+            after,
+            message: message.to_owned(),
+        })
+    }
+
+    pub(crate) fn apply(&self, git: &git2::Repository) -> Result<()> {
+        set(git, &self.name, self.after, &self.message)
+    }
+
+    pub(crate) fn revert(&self, git: &git2::Repository) -> Result<()> {
+        let message = format!("idgit: undo {}", self.message);
+        set(git, &self.name, self.before, &message)
+    }
+}
+
+/// The name of the reference HEAD points to, or `HEAD` itself if it is
+/// detached.
+pub(crate) fn head_name(git: &git2::Repository) -> Result<String> {
+    let head = git.find_reference("HEAD")?;
+    Ok(head.symbolic_target().unwrap_or("HEAD").to_owned())
+}
+
+fn target(git: &git2::Repository, name: &str) -> Result<Option<git2::Oid>> {
+    match git.find_reference(name) {
+        Ok(reference) => Ok(reference.target()),
+        Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn set(git: &git2::Repository, name: &str, to: Option<git2::Oid>, message: &str) -> Result<()> {
+    match to {
+        Some(id) if name == "HEAD" => git.set_head_detached(id)?,
+        Some(id) => {
+            git.reference(name, id, true, message)?;
+        }
+        None => match git.find_reference(name) {
+            Ok(mut reference) => reference.delete()?,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => (),
+            Err(err) => return Err(err.into()),
+        },
+    }
+    Ok(())
+}
@@ -1,19 +1,44 @@
-use std::{fmt, path::Path};
+use std::{
+    collections::{BTreeSet, HashSet},
+    ffi::OsStr,
+    fmt,
+    os::unix::ffi::OsStrExt,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+};
 
-use crate::{diff, file::File, Error, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    branch, commit,
+    diff::{self, Revision, Side},
+    file::File,
+    fingerprint::Fingerprint,
+    history::{Action, History},
+    index, patch, refs, serde_git, stash, workdir, DiffOptions, Error, HistoryBranch, HistoryEntry,
+    Log, LogOptions, Result, Status, StatusOptions, Subscription, Untracked, Worker,
+    WorkerResponse,
+};
 #[allow(unused)]
 use tracing::{debug, error, info, instrument, span, warn};
 
-pub struct Repo<'r> {
+pub struct Repo {
     pub(crate) internal: Internal,
-    history: undo::History<Change<'r>>,
+    history: History<Recorded>,
+    transaction: Option<Transaction>,
 }
 
-impl<'r> Repo<'r> {
+impl Repo {
+    /// Open the repository at `path`, along with any history saved by a
+    /// previous session.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
         let internal = Internal::open(path)?;
-        let history = undo::History::new();
-        Ok(Self { internal, history })
+        let history = History::load(&internal.history_path())?;
+        Ok(Self {
+            internal,
+            history,
+            transaction: None,
+        })
     }
 
     pub fn can_undo(&self) -> bool {
@@ -24,84 +49,1092 @@ impl<'r> Repo<'r> {
         self.history.can_redo()
     }
 
+    /// Undo the last change.
+    ///
+    /// Fails with [`Error::HistoryDiverged`] if the repository was modified
+    /// outside idgit since, as undoing could clobber those modifications.
     pub fn undo(&mut self) -> Result<()> {
+        self._undo(false)
+    }
+
+    /// Undo the last change even if the repository was modified outside
+    /// idgit since.
+    pub fn force_undo(&mut self) -> Result<()> {
+        self._undo(true)
+    }
+
+    /// Redo the last undone change.
+    ///
+    /// Fails with [`Error::HistoryDiverged`] if the repository was modified
+    /// outside idgit since, as redoing could clobber those modifications.
+    pub fn redo(&mut self) -> Result<()> {
+        self._redo(false)
+    }
+
+    /// Redo the last undone change even if the repository was modified
+    /// outside idgit since.
+    pub fn force_redo(&mut self) -> Result<()> {
+        self._redo(true)
+    }
+
+    /// Every change along the current branch of the history, oldest first,
+    /// including those that have been undone and could be redone.
+    pub fn history_entries(&self) -> Vec<HistoryEntry> {
+        self.history.entries()
+    }
+
+    /// Every branch of the history. Doing something new after undoing starts
+    /// a new branch rather than losing what was undone, so this includes
+    /// everything ever done.
+    pub fn history_branches(&self) -> Vec<HistoryBranch> {
+        self.history.branches()
+    }
+
+    /// Undo and redo until exactly the first `position` entries of `branch`
+    /// (and everything it forked from) are applied. Position `0` is where the
+    /// branch starts, so `goto(0, 0)` goes back to before anything was done.
+    ///
+    /// Stops at the first change that fails, such as one that would clobber
+    /// modifications made outside idgit.
+    pub fn goto(&mut self, branch: usize, position: usize) -> Result<()> {
+        if self.transaction.is_some() {
+            return Err(Error::UndoInTransaction);
+        }
+        let (common, target) = self
+            .history
+            .route(branch, position)
+            .ok_or(Error::HistoryPointNotFound(branch, position))?;
+
+        while self.history.depth() > common {
+            self.undo()?;
+        }
+        self.history.switch(branch);
+        self.save_history();
+        while self.history.depth() < target {
+            self.redo()?;
+        }
+        Ok(())
+    }
+
+    /// Run `f`, recording every change it makes as a single entry in the
+    /// history described by `label`, so they're undone and redone together.
+    ///
+    /// If `f` fails or panics whatever it had already changed is rolled back
+    /// and nothing is recorded. Undoing and redoing aren't allowed inside `f`.
+    /// Nested transactions become part of the outermost one.
+    ///
+    /// # Panics
+    /// If `f` panics, once its changes have been rolled back.
+    pub fn transaction<T, F>(&mut self, label: &str, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        if self.transaction.is_some() {
+            return f(self);
+        }
+
+        self.transaction = Some(Transaction {
+            changes: vec![],
+            before: self.internal.fingerprint_paths(&[])?,
+        });
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(self)));
+        let mut transaction = self
+            .transaction
+            .take()
+            .expect("Only we can end the transaction");
+
+        let result = result.unwrap_or_else(|payload| {
+            rollback(&mut transaction.changes, &mut self.internal);
+            panic::resume_unwind(payload)
+        });
+        let value = match result {
+            Ok(value) => value,
+            Err(err) => {
+                rollback(&mut transaction.changes, &mut self.internal);
+                return Err(err);
+            }
+        };
+        if transaction.changes.is_empty() {
+            return Ok(value);
+        }
+
+        let change = Change::Group {
+            label: label.to_owned(),
+            changes: transaction.changes,
+        };
+        let after = self.internal.fingerprint(&change)?;
+        self.history.push(Recorded {
+            change,
+            before: Some(transaction.before),
+            after: Some(after),
+        });
+        self.save_history();
+        Ok(value)
+    }
+
+    fn _undo(&mut self, force: bool) -> Result<()> {
+        if self.transaction.is_some() {
+            return Err(Error::UndoInTransaction);
+        }
+        let next = self.history.next_undo().ok_or(Error::UndoEmpty)?;
+        if !force {
+            self.internal.check(&next.change, next.after.as_ref())?;
+        }
+
         self.history
             .undo(&mut self.internal)
             .ok_or(Error::UndoEmpty)
-            .flatten()
+            .flatten()?;
+        self.save_history();
+        Ok(())
     }
 
-    pub fn redo(&mut self) -> Result<()> {
+    fn _redo(&mut self, force: bool) -> Result<()> {
+        if self.transaction.is_some() {
+            return Err(Error::UndoInTransaction);
+        }
+        let next = self.history.next_redo().ok_or(Error::RedoEmpty)?;
+        if !force {
+            self.internal.check(&next.change, next.before.as_ref())?;
+        }
+
         self.history
             .redo(&mut self.internal)
             .ok_or(Error::RedoEmpty)
-            .flatten()
+            .flatten()?;
+        self.save_history();
+        Ok(())
     }
 
     pub fn path(&self) -> &Path {
         self.internal.path()
     }
 
-    pub fn uncommitted_files(&self) -> Result<Vec<diff::Meta>> {
-        self.internal.uncommitted_files()
+    /// Every file with changes from HEAD to the workdir, filtered as
+    /// `options` says.
+    pub fn uncommitted_files(&self, options: &StatusOptions) -> Result<Vec<diff::Meta>> {
+        self.internal.uncommitted_files(options)
     }
 
     pub fn diff_details(&self, diff: &diff::Meta) -> Result<diff::Details> {
         self.internal.diff_details(diff)
     }
 
-    pub fn stage_file(&mut self, file: &'r File) -> Result<()> {
-        self.apply(Change::StageFile(file))
+    /// Details of the changes to every file from `from` to `to`, such as from
+    /// a commit's parent to the commit to show what it changed, or between
+    /// two branches to compare them.
+    pub fn diff(
+        &self,
+        from: &Revision,
+        to: &Revision,
+        options: &DiffOptions,
+    ) -> Result<Vec<diff::Details>> {
+        self.internal.diff(from, to, options)
+    }
+
+    /// The staged and unstaged changes to every path that has either.
+    pub fn status(&self) -> Result<Vec<Status>> {
+        self.internal.status()
+    }
+
+    /// Watch for changes made to the repository by anything, including
+    /// editors and other git tools, and get its status after each.
+    pub fn subscribe(&self) -> Result<Subscription> {
+        Subscription::new(self.path())
+    }
+
+    /// Start working out status and diffs on a background thread, with each
+    /// answer passed to `respond` on that thread. See [`Worker`].
+    pub fn worker<F>(&self, respond: F) -> Result<Worker>
+    where
+        F: FnMut(WorkerResponse) + Send + 'static,
+    {
+        Worker::new(self.path(), respond)
+    }
+
+    /// Details of the changes to a file from HEAD to the index.
+    pub fn staged_details(&self, diff: &diff::Meta) -> Result<diff::Details> {
+        let path = diff.file().rel_path_required()?;
+        self.internal.side_details(Side::Staged, path)
+    }
+
+    /// Details of the changes to a file from the index to the workdir.
+    pub fn unstaged_details(&self, diff: &diff::Meta) -> Result<diff::Details> {
+        let path = diff.file().rel_path_required()?;
+        self.internal.side_details(Side::Unstaged, path)
+    }
+
+    /// Stage whatever change `meta` describes. A rename is staged as removing
+    /// the old path and adding the new one, in a single step in the history.
+    pub fn stage(&mut self, meta: &diff::Meta) -> Result<()> {
+        match meta {
+            diff::Meta::Renamed { old, new } => {
+                let old = old.rel_path_required()?.to_path_buf();
+                let new = new.rel_path_required()?.to_path_buf();
+                let label = format!("Staged rename of {} to {}", old.display(), new.display());
+                self.transaction(&label, |repo| {
+                    repo.apply(Change::stage_file(old))?;
+                    repo.apply(Change::stage_file(new))
+                })
+            }
+            _ => self.stage_file(meta.file()),
+        }
+    }
+
+    /// Unstage whatever change `meta` describes, including both sides of a
+    /// rename.
+    pub fn unstage(&mut self, meta: &diff::Meta) -> Result<()> {
+        match meta {
+            diff::Meta::Renamed { old, new } => {
+                let old = old.rel_path_required()?.to_path_buf();
+                let new = new.rel_path_required()?.to_path_buf();
+                let label = format!("Unstaged rename of {} to {}", old.display(), new.display());
+                self.transaction(&label, |repo| {
+                    repo.apply(Change::unstage_file(old))?;
+                    repo.apply(Change::unstage_file(new))
+                })
+            }
+            _ => self.unstage_file(meta.file()),
+        }
+    }
+
+    /// Make the index match the workdir for `file`, whether it was modified,
+    /// deleted, changed type, or had its executable bit flipped.
+    pub fn stage_file(&mut self, file: &File) -> Result<()> {
+        let path = file.rel_path_required()?.to_path_buf();
+        self.apply(Change::stage_file(path))
+    }
+
+    pub fn unstage_file(&mut self, file: &File) -> Result<()> {
+        let path = file.rel_path_required()?.to_path_buf();
+        self.apply(Change::unstage_file(path))
+    }
+
+    /// Stage every uncommitted file that isn't ignored, as a single step in
+    /// the history.
+    pub fn stage_all(&mut self) -> Result<()> {
+        self.stage_paths("Staged all files", &[])
+    }
+
+    /// Unstage everything, as a single step in the history.
+    pub fn unstage_all(&mut self) -> Result<()> {
+        self.unstage_paths("Unstaged all files", &[])
+    }
+
+    /// Stage every uncommitted file that matches `pathspec` and isn't
+    /// ignored, as a single step in the history. Patterns are as in
+    /// `git add`, so a directory matches everything in it.
+    pub fn stage_matching(&mut self, pathspec: &[&str]) -> Result<()> {
+        self.stage_paths(&format!("Staged {}", pathspec.join(" ")), pathspec)
+    }
+
+    /// Unstage every file that matches `pathspec`, as a single step in the
+    /// history.
+    pub fn unstage_matching(&mut self, pathspec: &[&str]) -> Result<()> {
+        self.unstage_paths(&format!("Unstaged {}", pathspec.join(" ")), pathspec)
+    }
+
+    fn stage_paths(&mut self, label: &str, pathspec: &[&str]) -> Result<()> {
+        let paths = self.internal.uncommitted_paths(pathspec)?;
+        self.transaction(label, |repo| {
+            paths
+                .into_iter()
+                .try_for_each(|path| repo.apply(Change::stage_file(path)))
+        })
+    }
+
+    fn unstage_paths(&mut self, label: &str, pathspec: &[&str]) -> Result<()> {
+        let paths = self.internal.staged_paths(pathspec)?;
+        self.transaction(label, |repo| {
+            paths
+                .into_iter()
+                .try_for_each(|path| repo.apply(Change::unstage_file(path)))
+        })
+    }
+
+    /// Stage a single hunk of the unstaged changes to a file. `details` must
+    /// come from [`Repo::unstaged_details`].
+    pub fn stage_hunk(&mut self, details: &diff::Details, hunk: &diff::Hunk) -> Result<()> {
+        let path = Self::path_on_side(details, Side::Unstaged)?;
+        self.apply(Change::StageHunk {
+            path,
+            hunk: hunk.id(),
+            update: None,
+        })
+    }
+
+    /// Unstage a single hunk of the staged changes to a file. `details` must
+    /// come from [`Repo::staged_details`].
+    pub fn unstage_hunk(&mut self, details: &diff::Details, hunk: &diff::Hunk) -> Result<()> {
+        let path = Self::path_on_side(details, Side::Staged)?;
+        self.apply(Change::UnstageHunk {
+            path,
+            hunk: hunk.id(),
+            update: None,
+        })
+    }
+
+    /// Stage only the given added and deleted lines of the unstaged changes to
+    /// a file. `details` must come from [`Repo::unstaged_details`], and every
+    /// line must still be there.
+    pub fn stage_lines(&mut self, details: &diff::Details, lines: &[diff::LineId]) -> Result<()> {
+        let path = Self::path_on_side(details, Side::Unstaged)?;
+        if lines.is_empty() {
+            return Err(Error::NoLinesSelected(path));
+        }
+        self.apply(Change::StageLines {
+            path,
+            lines: lines.to_vec(),
+            update: None,
+        })
+    }
+
+    /// Unstage only the given added and deleted lines of the staged changes to
+    /// a file. `details` must come from [`Repo::staged_details`], and every
+    /// line must still be there.
+    pub fn unstage_lines(&mut self, details: &diff::Details, lines: &[diff::LineId]) -> Result<()> {
+        let path = Self::path_on_side(details, Side::Staged)?;
+        if lines.is_empty() {
+            return Err(Error::NoLinesSelected(path));
+        }
+        self.apply(Change::UnstageLines {
+            path,
+            lines: lines.to_vec(),
+            update: None,
+        })
+    }
+
+    /// Throw away the unstaged changes to a file by overwriting it with the
+    /// version in the index or HEAD. Files that aren't there are deleted.
+    ///
+    /// The current contents are saved in the object database first so that
+    /// this can be undone.
+    pub fn discard_file(&mut self, file: &File, to: DiscardTo) -> Result<()> {
+        let path = file.rel_path_required()?.to_path_buf();
+        self.apply(Change::DiscardFile {
+            path,
+            to,
+            update: None,
+        })
+    }
+
+    /// Throw away a single hunk of the unstaged changes to a file. `details`
+    /// must come from [`Repo::unstaged_details`].
+    ///
+    /// The current contents are saved in the object database first so that
+    /// this can be undone.
+    pub fn discard_hunk(&mut self, details: &diff::Details, hunk: &diff::Hunk) -> Result<()> {
+        let path = Self::path_on_side(details, Side::Unstaged)?;
+        self.apply(Change::DiscardHunk {
+            path,
+            hunk: hunk.id(),
+            update: None,
+        })
+    }
+
+    /// The path `details` are of, checking they're the `side` of the
+    /// uncommitted changes that's about to be changed
+    fn path_on_side(details: &diff::Details, side: Side) -> Result<PathBuf> {
+        let path = details.meta().file().rel_path_required()?.to_path_buf();
+        if details.side() == Some(side) {
+            Ok(path)
+        } else {
+            Err(Error::WrongSide(path, side))
+        }
+    }
+
+    /// Commit the index on HEAD, with the signature from the repo's config.
+    ///
+    /// Undoing moves the branch back to where it was, leaving the changes
+    /// staged. Redoing moves it forward to the same commit.
+    pub fn commit(&mut self, message: &str, options: &commit::Options) -> Result<()> {
+        self.apply(Change::Commit {
+            message: message.to_owned(),
+            options: options.clone(),
+            update: None,
+        })
+    }
+
+    /// Fold the index into HEAD, optionally changing its message.
+    ///
+    /// Undoing moves the branch back to the original commit.
+    pub fn amend(&mut self, message: Option<&str>, options: &commit::Options) -> Result<()> {
+        self.apply(Change::Amend {
+            message: message.map(str::to_owned),
+            options: options.clone(),
+            update: None,
+        })
+    }
+
+    /// Change the message of HEAD, leaving its tree alone.
+    ///
+    /// Undoing moves the branch back to the original commit.
+    pub fn reword(&mut self, message: &str, options: &commit::Options) -> Result<()> {
+        self.apply(Change::Reword {
+            message: message.to_owned(),
+            options: options.clone(),
+            update: None,
+        })
+    }
+
+    /// Every local branch, sorted by name.
+    pub fn branches(&self) -> Result<Vec<branch::Branch>> {
+        branch::Branch::list(&self.internal.git)
+    }
+
+    /// Create a branch pointing at `at`, or at HEAD if `None`.
+    ///
+    /// Undoing deletes it again.
+    pub fn create_branch(&mut self, name: &str, at: Option<git2::Oid>) -> Result<()> {
+        self.apply(Change::CreateBranch {
+            name: name.to_owned(),
+            at,
+            update: None,
+        })
+    }
+
+    /// Delete a branch that isn't checked out, whether or not it's merged,
+    /// along with its config such as the upstream it tracks.
+    ///
+    /// Undoing recreates it pointing at the same commit, with the same config.
+    pub fn delete_branch(&mut self, name: &str) -> Result<()> {
+        self.apply(Change::DeleteBranch {
+            name: name.to_owned(),
+            update: None,
+        })
+    }
+
+    /// Rename a branch, along with its reflog and config. HEAD follows it if
+    /// it's checked out.
+    pub fn rename_branch(&mut self, from: &str, to: &str) -> Result<()> {
+        self.apply(Change::RenameBranch {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            update: None,
+        })
+    }
+
+    /// Point HEAD at a branch, updating the index and workdir to match.
+    ///
+    /// Fails with [`Error::CheckoutConflicts`] without changing anything if
+    /// that would overwrite uncommitted changes. Undoing checks out whatever
+    /// was checked out before, in the same way.
+    pub fn checkout_branch(&mut self, name: &str) -> Result<()> {
+        self.apply(Change::CheckoutBranch {
+            name: name.to_owned(),
+            update: None,
+        })
+    }
+
+    /// The commits `options` asks for, read lazily as the log is iterated.
+    pub fn log(&self, options: &LogOptions) -> Result<Log<'_>> {
+        self.internal.log(options)
+    }
+
+    /// Every stash, newest first.
+    pub fn stashes(&self) -> Result<Vec<stash::Stash>> {
+        stash::list(&self.internal.git)
+    }
+
+    /// Save the changes to the files `scope` covers as a new stash, and
+    /// reset those files to HEAD in both the index and the workdir. With
+    /// [`stash::Scope::Staged`] only the staged changes are stashed and taken
+    /// out of the workdir, leaving any unstaged changes where they are.
+    ///
+    /// Undoing puts the changes back and removes the stash.
+    pub fn stash_push(&mut self, message: Option<&str>, scope: &stash::Scope) -> Result<()> {
+        let paths = self.internal.stash_paths(scope)?;
+        if paths.is_empty() {
+            return Err(Error::NothingToStash);
+        }
+        self.apply(Change::StashPush {
+            message: message.map(str::to_owned),
+            paths,
+            only_staged: matches!(scope, stash::Scope::Staged),
+            update: None,
+        })
+    }
+
+    /// Details of the changes to each file in the stash at `index`, from the
+    /// commit it was made on to what was in the workdir.
+    pub fn stash_show(&self, index: usize) -> Result<Vec<diff::Details>> {
+        let git = &self.internal.git;
+        let trees = stash::Trees::of(git, stash::get(git, index)?)?;
+        let diff = git.diff_tree_to_tree(Some(&trees.base), Some(&trees.unstaged), None)?;
+        Internal::all_details(&diff)
+    }
+
+    /// Restore the staged and unstaged changes in the stash at `index`,
+    /// leaving it in the list.
+    ///
+    /// The changes are merged with any made since to the same files, whether
+    /// committed or not. Fails with [`Error::StashConflicts`] without
+    /// changing anything if they overlap.
+    pub fn stash_apply(&mut self, index: usize) -> Result<()> {
+        let git = &self.internal.git;
+        let id = stash::get(git, index)?;
+        let paths = stash::Trees::of(git, id)?.paths(git)?;
+        self.apply(Change::StashApply {
+            index,
+            id,
+            paths,
+            update: None,
+        })
+    }
+
+    /// Apply the stash at `index` and then drop it, as a single step in the
+    /// history.
+    pub fn stash_pop(&mut self, index: usize) -> Result<()> {
+        self.transaction(&format!("Popped stash@{{{}}}", index), |repo| {
+            repo.stash_apply(index)?;
+            repo.stash_drop(index)
+        })
     }
 
-    pub fn unstage_file(&mut self, file: &'r File) -> Result<()> {
-        self.apply(Change::UnstageFile(file))
+    /// Remove the stash at `index` from the list.
+    ///
+    /// Undoing puts it back in the same place.
+    pub fn stash_drop(&mut self, index: usize) -> Result<()> {
+        self.apply(Change::StashDrop {
+            index,
+            update: None,
+        })
     }
 
-    fn apply(&mut self, change: Change<'r>) -> Result<()> {
-        self.history.apply(&mut self.internal, change)
+    fn apply(&mut self, mut change: Change) -> Result<()> {
+        if let Some(transaction) = &mut self.transaction {
+            // Anything an earlier change in the transaction touched is already
+            // fingerprinted as it was before the transaction started.
+            self.internal
+                .add_to_fingerprint(&mut transaction.before, &change)?;
+            change.apply(&mut self.internal)?;
+            transaction.changes.push(change);
+            return Ok(());
+        }
+
+        self.history
+            .apply(&mut self.internal, Recorded::new(change))?;
+        self.save_history();
+        Ok(())
+    }
+
+    /// The change itself has already happened by the time we save, so failing
+    /// to save only costs the ability to undo it in a future session.
+    fn save_history(&self) {
+        let path = self.internal.history_path();
+        if let Err(err) = self.history.save(&path) {
+            warn!("Failed to save history to {:?}: {}", path, err);
+        }
     }
 }
 
-impl fmt::Debug for Repo<'_> {
+impl fmt::Debug for Repo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let history = format!("{}", self.history.display());
         f.debug_struct("Repo")
             .field("internal", &self.internal)
-            .field("history", &history)
+            .field("history", &self.history)
+            .field("transaction", &self.transaction)
             .finish_non_exhaustive()
     }
 }
 
-#[derive(Debug, Clone)]
-enum Change<'r> {
-    StageFile(&'r File),
-    UnstageFile(&'r File),
+/// What to overwrite a file with when discarding changes to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiscardTo {
+    Index,
+    Head,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Change {
+    StageFile {
+        path: PathBuf,
+        update: Option<index::Update>,
+    },
+    UnstageFile {
+        path: PathBuf,
+        update: Option<index::Update>,
+    },
+    StageHunk {
+        path: PathBuf,
+        hunk: diff::HunkId,
+        update: Option<index::Update>,
+    },
+    UnstageHunk {
+        path: PathBuf,
+        hunk: diff::HunkId,
+        update: Option<index::Update>,
+    },
+    StageLines {
+        path: PathBuf,
+        lines: Vec<diff::LineId>,
+        update: Option<index::Update>,
+    },
+    UnstageLines {
+        path: PathBuf,
+        lines: Vec<diff::LineId>,
+        update: Option<index::Update>,
+    },
+    DiscardFile {
+        path: PathBuf,
+        to: DiscardTo,
+        update: Option<workdir::Update>,
+    },
+    DiscardHunk {
+        path: PathBuf,
+        hunk: diff::HunkId,
+        update: Option<workdir::Update>,
+    },
+    Commit {
+        message: String,
+        options: commit::Options,
+        update: Option<refs::Update>,
+    },
+    Amend {
+        message: Option<String>,
+        options: commit::Options,
+        update: Option<refs::Update>,
+    },
+    Reword {
+        message: String,
+        options: commit::Options,
+        update: Option<refs::Update>,
+    },
+    CreateBranch {
+        name: String,
+        #[serde(with = "serde_git::option_oid")]
+        at: Option<git2::Oid>,
+        update: Option<refs::Update>,
+    },
+    DeleteBranch {
+        name: String,
+        update: Option<branch::Delete>,
+    },
+    RenameBranch {
+        from: String,
+        to: String,
+        update: Option<branch::Rename>,
+    },
+    CheckoutBranch {
+        name: String,
+        update: Option<branch::Checkout>,
+    },
+    StashPush {
+        message: Option<String>,
+        paths: Vec<PathBuf>,
+        only_staged: bool,
+        update: Option<stash::Push>,
+    },
+    StashApply {
+        index: usize,
+        #[serde(with = "serde_git::oid")]
+        id: git2::Oid,
+        paths: Vec<PathBuf>,
+        update: Option<stash::Apply>,
+    },
+    StashDrop {
+        index: usize,
+        update: Option<stash::Remove>,
+    },
+    /// Changes made in a [`Repo::transaction`]
+    Group { label: String, changes: Vec<Change> },
 }
 
-impl<'r> undo::Action for Change<'r> {
+/// Changes made so far in a [`Repo::transaction`], which are applied but not
+/// yet in the history
+#[derive(Debug)]
+struct Transaction {
+    changes: Vec<Change>,
+    before: Fingerprint,
+}
+
+impl Action for Change {
     type Target = Internal;
-    type Output = ();
-    type Error = Error;
 
-    fn apply(&mut self, target: &mut Self::Target) -> undo::Result<Self> {
+    fn apply(&mut self, target: &mut Self::Target) -> Result<()> {
+        match self {
+            Change::StageFile { path, update } => {
+                target.apply_update(update, |target| target.stage_file(path))
+            }
+            Change::UnstageFile { path, update } => {
+                target.apply_update(update, |target| target.unstage_file(path))
+            }
+            Change::StageHunk { path, hunk, update } => {
+                target.apply_update(update, |target| target.stage_hunk(path, hunk))
+            }
+            Change::UnstageHunk { path, hunk, update } => {
+                target.apply_update(update, |target| target.unstage_hunk(path, hunk))
+            }
+            Change::StageLines {
+                path,
+                lines,
+                update,
+            } => target.apply_update(update, |target| target.stage_lines(path, lines)),
+            Change::UnstageLines {
+                path,
+                lines,
+                update,
+            } => target.apply_update(update, |target| target.unstage_lines(path, lines)),
+            Change::DiscardFile { path, to, update } => {
+                target.apply_update(update, |target| target.discard_file(path, *to))
+            }
+            Change::DiscardHunk { path, hunk, update } => {
+                target.apply_update(update, |target| target.discard_hunk(path, hunk))
+            }
+            Change::Commit {
+                message,
+                options,
+                update,
+            } => target.apply_update(update, |target| target.commit(message, options)),
+            Change::Amend {
+                message,
+                options,
+                update,
+            } => target.apply_update(update, |target| target.amend(message.as_deref(), options)),
+            Change::Reword {
+                message,
+                options,
+                update,
+            } => target.apply_update(update, |target| target.reword(message, options)),
+            Change::CreateBranch { name, at, update } => {
+                target.apply_update(update, |target| target.create_branch(name, *at))
+            }
+            Change::DeleteBranch { name, update } => {
+                target.apply_update(update, |target| branch::Delete::record(&target.git, name))
+            }
+            Change::RenameBranch { from, to, update } => target.apply_update(update, |target| {
+                branch::Rename::record(&target.git, from, to)
+            }),
+            Change::CheckoutBranch { name, update } => {
+                target.apply_update(update, |target| branch::Checkout::record(&target.git, name))
+            }
+            Change::StashPush {
+                message,
+                paths,
+                only_staged,
+                update,
+            } => target.apply_update(update, |target| {
+                target.stash_push(message.as_deref(), paths, *only_staged)
+            }),
+            Change::StashApply {
+                id, paths, update, ..
+            } => target.apply_update(update, |target| target.stash_apply(*id, paths)),
+            Change::StashDrop { index, update } => {
+                target.apply_update(update, |target| stash::Remove::record(&target.git, *index))
+            }
+            Change::Group { changes, .. } => {
+                for i in 0..changes.len() {
+                    let (done, rest) = changes.split_at_mut(i);
+                    if let Err(err) = rest[0].apply(target) {
+                        rollback(done, target);
+                        return Err(err);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn undo(&mut self, target: &mut Self::Target) -> Result<()> {
         match self {
-            Change::StageFile(file) => target.stage_file(file),
-            Change::UnstageFile(file) => target.unstage_file(file),
+            Change::StageFile { update, .. }
+            | Change::UnstageFile { update, .. }
+            | Change::StageHunk { update, .. }
+            | Change::UnstageHunk { update, .. }
+            | Change::StageLines { update, .. }
+            | Change::UnstageLines { update, .. } => target.revert_update(update.as_ref()),
+            Change::DiscardFile { update, .. } | Change::DiscardHunk { update, .. } => {
+                target.revert_update(update.as_ref())
+            }
+            // Committing doesn't touch the index, so moving the branch back is
+            // enough to restore it to how it was before.
+            Change::Commit { update, .. }
+            | Change::Amend { update, .. }
+            | Change::Reword { update, .. }
+            | Change::CreateBranch { update, .. } => target.revert_update(update.as_ref()),
+            Change::DeleteBranch { update, .. } => target.revert_update(update.as_ref()),
+            Change::RenameBranch { update, .. } => target.revert_update(update.as_ref()),
+            Change::CheckoutBranch { update, .. } => target.revert_update(update.as_ref()),
+            Change::StashPush { update, .. } => target.revert_update(update.as_ref()),
+            Change::StashApply { update, .. } => target.revert_update(update.as_ref()),
+            Change::StashDrop { update, .. } => target.revert_update(update.as_ref()),
+            Change::Group { changes, .. } => {
+                for i in (0..changes.len()).rev() {
+                    let (rest, undone) = changes.split_at_mut(i + 1);
+                    if let Err(err) = rest[i].undo(target) {
+                        rollforward(undone, target);
+                        return Err(err);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Undo changes that were just applied, newest first, so a group of changes
+/// that failed partway through leaves nothing behind.
+fn rollback(applied: &mut [Change], target: &mut Internal) {
+    for change in applied.iter_mut().rev() {
+        if let Err(err) = change.undo(target) {
+            error!("Failed to roll back {}: {}", change, err);
+        }
+    }
+}
+
+/// Redo changes that were just undone, oldest first, so a group of changes
+/// that failed partway through undoing is left as it was.
+fn rollforward(undone: &mut [Change], target: &mut Internal) {
+    for change in undone {
+        if let Err(err) = change.redo(target) {
+            error!("Failed to roll forward {}: {}", change, err);
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::StageFile { path, .. } => write!(f, "Staged {}", path.display()),
+            Change::UnstageFile { path, .. } => write!(f, "Unstaged {}", path.display()),
+            Change::StageHunk { path, .. } => write!(f, "Staged a hunk of {}", path.display()),
+            Change::UnstageHunk { path, .. } => {
+                write!(f, "Unstaged a hunk of {}", path.display())
+            }
+            Change::StageLines { path, lines, .. } => {
+                write!(f, "Staged {} of {}", count_lines(lines), path.display())
+            }
+            Change::UnstageLines { path, lines, .. } => {
+                write!(f, "Unstaged {} of {}", count_lines(lines), path.display())
+            }
+            Change::DiscardFile {
+                path,
+                to: DiscardTo::Index,
+                ..
+            } => write!(f, "Discarded unstaged changes to {}", path.display()),
+            Change::DiscardFile {
+                path,
+                to: DiscardTo::Head,
+                ..
+            } => write!(f, "Discarded all changes to {}", path.display()),
+            Change::DiscardHunk { path, .. } => {
+                write!(f, "Discarded a hunk of {}", path.display())
+            }
+            Change::Commit { message, .. } => write!(f, "Committed \"{}\"", summary(message)),
+            Change::Amend {
+                message: Some(message),
+                ..
+            } => write!(f, "Amended HEAD as \"{}\"", summary(message)),
+            Change::Amend { message: None, .. } => write!(f, "Amended HEAD"),
+            Change::Reword { message, .. } => {
+                write!(f, "Reworded HEAD as \"{}\"", summary(message))
+            }
+            Change::CreateBranch { name, .. } => write!(f, "Created branch {}", name),
+            Change::DeleteBranch { name, .. } => write!(f, "Deleted branch {}", name),
+            Change::RenameBranch { from, to, .. } => {
+                write!(f, "Renamed branch {} to {}", from, to)
+            }
+            Change::CheckoutBranch { name, .. } => write!(f, "Checked out {}", name),
+            Change::StashPush {
+                message: Some(message),
+                ..
+            } => write!(f, "Stashed \"{}\"", summary(message)),
+            Change::StashPush { paths, .. } => write!(f, "Stashed {}", count_files(paths)),
+            Change::StashApply { index, .. } => write!(f, "Applied stash@{{{}}}", index),
+            Change::StashDrop { index, .. } => write!(f, "Dropped stash@{{{}}}", index),
+            Change::Group { label, .. } => write!(f, "{}", label),
         }
     }
+}
+
+fn count_lines(lines: &[diff::LineId]) -> String {
+    if lines.len() == 1 {
+        "1 line".to_owned()
+    } else {
+        format!("{} lines", lines.len())
+    }
+}
+
+fn count_files(paths: &[PathBuf]) -> String {
+    if paths.len() == 1 {
+        "1 file".to_owned()
+    } else {
+        format!("{} files", paths.len())
+    }
+}
+
+/// The first line of a commit message
+fn summary(message: &str) -> &str {
+    message.lines().next().unwrap_or("")
+}
+
+impl Change {
+    fn stage_file(path: PathBuf) -> Self {
+        Change::StageFile { path, update: None }
+    }
 
-    fn undo(&mut self, target: &mut Self::Target) -> undo::Result<Self> {
+    fn unstage_file(path: PathBuf) -> Self {
+        Change::UnstageFile { path, update: None }
+    }
+
+    /// Paths whose workdir contents undoing or redoing this would overwrite
+    fn workdir_paths(&self) -> Vec<&Path> {
         match self {
-            Change::StageFile(file) => target.unstage_file(file),
-            Change::UnstageFile(file) => target.stage_file(file),
+            Change::DiscardFile { path, .. } | Change::DiscardHunk { path, .. } => {
+                vec![path.as_path()]
+            }
+            Change::StashPush { paths, .. } | Change::StashApply { paths, .. } => {
+                paths.iter().map(PathBuf::as_path).collect()
+            }
+            Change::Group { changes, .. } => {
+                let mut paths = vec![];
+                for path in changes.iter().flat_map(Change::workdir_paths) {
+                    if !paths.contains(&path) {
+                        paths.push(path);
+                    }
+                }
+                paths
+            }
+            _ => vec![],
+        }
+    }
+}
+
+/// A change along with the state of the repository before and after it was
+/// last applied, so we can check nothing else touched the repository before
+/// undoing or redoing it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Recorded {
+    change: Change,
+    before: Option<Fingerprint>,
+    after: Option<Fingerprint>,
+}
+
+impl Recorded {
+    fn new(change: Change) -> Self {
+        Self {
+            change,
+            before: None,
+            after: None,
         }
     }
 }
 
-impl fmt::Display for Change<'_> {
+impl Action for Recorded {
+    type Target = Internal;
+
+    fn apply(&mut self, target: &mut Self::Target) -> Result<()> {
+        self.before = Some(target.fingerprint(&self.change)?);
+        self.change.apply(target)?;
+        self.after = Some(target.fingerprint(&self.change)?);
+        Ok(())
+    }
+
+    fn undo(&mut self, target: &mut Self::Target) -> Result<()> {
+        self.change.undo(target)
+    }
+}
+
+impl fmt::Display for Recorded {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
+        fmt::Display::fmt(&self.change, f)
+    }
+}
+
+/// A recorded change to part of the repository that can be replayed in
+/// either direction.
+trait Replay {
+    fn apply(&self, target: &Internal) -> Result<()>;
+    fn revert(&self, target: &Internal) -> Result<()>;
+}
+
+impl Replay for index::Update {
+    fn apply(&self, target: &Internal) -> Result<()> {
+        self.apply(&mut target.index()?)
+    }
+
+    fn revert(&self, target: &Internal) -> Result<()> {
+        self.revert(&mut target.index()?)
+    }
+}
+
+impl Replay for refs::Update {
+    fn apply(&self, target: &Internal) -> Result<()> {
+        self.apply(&target.git)
+    }
+
+    fn revert(&self, target: &Internal) -> Result<()> {
+        self.revert(&target.git)
+    }
+}
+
+impl Replay for branch::Delete {
+    fn apply(&self, target: &Internal) -> Result<()> {
+        self.apply(&target.git)
+    }
+
+    fn revert(&self, target: &Internal) -> Result<()> {
+        self.revert(&target.git)
+    }
+}
+
+impl Replay for branch::Rename {
+    fn apply(&self, target: &Internal) -> Result<()> {
+        self.apply(&target.git)
+    }
+
+    fn revert(&self, target: &Internal) -> Result<()> {
+        self.revert(&target.git)
+    }
+}
+
+impl Replay for branch::Checkout {
+    fn apply(&self, target: &Internal) -> Result<()> {
+        self.apply(&target.git)
+    }
+
+    fn revert(&self, target: &Internal) -> Result<()> {
+        self.revert(&target.git)
+    }
+}
+
+impl Replay for stash::Push {
+    fn apply(&self, target: &Internal) -> Result<()> {
+        self.apply(&target.git, &mut target.index()?, target.path())
+    }
+
+    fn revert(&self, target: &Internal) -> Result<()> {
+        self.revert(&target.git, &mut target.index()?, target.path())
+    }
+}
+
+impl Replay for stash::Apply {
+    fn apply(&self, target: &Internal) -> Result<()> {
+        self.apply(&target.git, &mut target.index()?, target.path())
+    }
+
+    fn revert(&self, target: &Internal) -> Result<()> {
+        self.revert(&target.git, &mut target.index()?, target.path())
+    }
+}
+
+impl Replay for stash::Remove {
+    fn apply(&self, target: &Internal) -> Result<()> {
+        self.apply(&target.git)
+    }
+
+    fn revert(&self, target: &Internal) -> Result<()> {
+        self.revert(&target.git)
+    }
+}
+
+impl Replay for workdir::Update {
+    fn apply(&self, target: &Internal) -> Result<()> {
+        self.apply(&target.git, target.path())
+    }
+
+    fn revert(&self, target: &Internal) -> Result<()> {
+        self.revert(&target.git, target.path())
     }
 }
 
@@ -113,11 +1146,30 @@ pub(crate) struct Internal {
 }
 
 impl Internal {
-    fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
         let git = git2::Repository::open(path)?;
         Ok(Self { git })
     }
 
+    /// The `.git` directory
+    pub(crate) fn git_dir(&self) -> &Path {
+        self.git.path()
+    }
+
+    pub(crate) fn log(&self, options: &LogOptions) -> Result<Log<'_>> {
+        Log::new(&self.git, options)
+    }
+
+    /// If `path` matches a gitignore rule. Tracked files can still match.
+    pub(crate) fn is_ignored(&self, path: &Path) -> Result<bool> {
+        Ok(self.git.status_should_ignore(path)?)
+    }
+
+    /// Where the history is saved between sessions
+    fn history_path(&self) -> PathBuf {
+        self.git.path().join("idgit").join("history.json")
+    }
+
     pub(crate) fn path(&self) -> &Path {
         let path = self.git.path();
         if path.ends_with(".git") {
@@ -127,11 +1179,18 @@ impl Internal {
         }
     }
 
-    fn head_assuming_born(&self) -> std::result::Result<git2::Tree, git2::Error> {
-        self.git.head()?.peel_to_commit()?.tree()
+    /// The index, re-read from disk if something else changed it.
+    pub(crate) fn index(&self) -> Result<git2::Index> {
+        let mut index = self.git.index()?;
+        index.read(false)?;
+        Ok(index)
     }
 
-    fn head(&self) -> Result<Option<git2::Tree>> {
+    fn head_assuming_born(&self) -> std::result::Result<git2::Commit, git2::Error> {
+        self.git.head()?.peel_to_commit()
+    }
+
+    fn head_commit(&self) -> Result<Option<git2::Commit>> {
         match self.head_assuming_born() {
             Ok(head) => Ok(Some(head)),
             Err(err) if err.code() == git2::ErrorCode::UnbornBranch => Ok(None),
@@ -139,13 +1198,78 @@ impl Internal {
         }
     }
 
-    fn uncommitted_files(&self) -> Result<Vec<diff::Meta>> {
+    fn head(&self) -> Result<Option<git2::Tree>> {
+        match self.head_commit()? {
+            Some(commit) => Ok(Some(commit.tree()?)),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn uncommitted_files(&self, options: &StatusOptions) -> Result<Vec<diff::Meta>> {
+        self.uncommitted_files_unless(options, &|| false)
+    }
+
+    /// Like [`Self::uncommitted_files`], but gives up with
+    /// [`Error::Cancelled`] once `cancelled` returns true.
+    ///
+    /// A single diff can't be interrupted, so the workdir is diffed a piece at
+    /// a time, checking in between: each of the pathspecs in `options`, or if
+    /// there aren't any each top-level path. Renames and copies are found once
+    /// the pieces are merged, so they're still found between pieces.
+    pub(crate) fn uncommitted_files_unless(
+        &self,
+        options: &StatusOptions,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<Vec<diff::Meta>> {
         let head = self.head()?;
-        let mut opts = Self::uncommitted_opts();
 
-        let deltas = self
-            .git
-            .diff_tree_to_workdir_with_index(head.as_ref(), Some(&mut opts))?
+        let pieces = if options.pathspecs().is_empty() {
+            self.top_level_paths()?
+                .into_iter()
+                .map(|path| {
+                    let mut opts = options.to_git2();
+                    opts.disable_pathspec_match(true).pathspec(path);
+                    opts
+                })
+                .collect::<Vec<_>>()
+        } else {
+            options
+                .pathspecs()
+                .iter()
+                .map(|spec| {
+                    let mut opts = options.to_git2();
+                    opts.pathspec(spec);
+                    opts
+                })
+                .collect()
+        };
+
+        let mut diff: Option<git2::Diff> = None;
+        for mut opts in pieces {
+            if cancelled() {
+                return Err(Error::Cancelled);
+            }
+            let piece = self
+                .git
+                .diff_tree_to_workdir_with_index(head.as_ref(), Some(&mut opts))?;
+            match &mut diff {
+                Some(diff) => diff.merge(&piece)?,
+                None => diff = Some(piece),
+            }
+        }
+        if cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let mut diff = match diff {
+            Some(diff) => diff,
+            None => return Ok(vec![]),
+        };
+
+        if let Some(mut find) = options.find_similar() {
+            diff.find_similar(Some(&mut find))?;
+        }
+
+        let deltas = diff
             .deltas()
             .map(|delta| diff::Meta::from_git2(&delta))
             .collect();
@@ -153,30 +1277,85 @@ impl Internal {
         Ok(deltas)
     }
 
-    fn diff_details(&self, meta: &diff::Meta) -> Result<diff::Details> {
-        match meta {
-            crate::Meta::Added(f)
-            | crate::Meta::Deleted(f)
-            | crate::Meta::Modified { new: f, .. }
-            | crate::Meta::Renamed { new: f, .. }
-            | crate::Meta::Copied { new: f, .. }
-            | crate::Meta::Ignored(f)
-            | crate::Meta::Untracked(f)
-            | crate::Meta::Typechange { new: f, .. }
-            | crate::Meta::Unreadable(f)
-            | crate::Meta::Conflicted { new: f, .. } => {
-                let path = f.rel_path_required()?;
-                self._diff_details(path)
+    /// Every path at the top of the repo in HEAD, the index or the workdir
+    fn top_level_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = BTreeSet::new();
+        if let Some(head) = self.head()? {
+            for entry in &head {
+                paths.insert(PathBuf::from(OsStr::from_bytes(entry.name_bytes())));
+            }
+        }
+        for entry in self.index()?.iter() {
+            let path = Path::new(OsStr::from_bytes(&entry.path));
+            if let Some(first) = path.components().next() {
+                paths.insert(PathBuf::from(first.as_os_str()));
             }
         }
+        for entry in std::fs::read_dir(self.path())? {
+            let name = entry?.file_name();
+            if name != ".git" {
+                paths.insert(PathBuf::from(name));
+            }
+        }
+        Ok(paths.into_iter().collect())
+    }
+
+    pub(crate) fn status(&self) -> Result<Vec<Status>> {
+        let mut opts = git2::StatusOptions::new();
+        opts.include_untracked(true)
+            .include_ignored(true)
+            .include_unmodified(false)
+            .include_unreadable(true);
+
+        let statuses = self
+            .git
+            .statuses(Some(&mut opts))?
+            .iter()
+            .map(|entry| Status::from_git2(&entry))
+            .collect();
+
+        Ok(statuses)
+    }
+
+    fn diff_details(&self, meta: &diff::Meta) -> Result<diff::Details> {
+        self.diff_details_unless(meta, &|| false)
+    }
+
+    /// Like [`Self::diff_details`], but gives up with [`Error::Cancelled`] as
+    /// soon as `cancelled` returns true.
+    pub(crate) fn diff_details_unless(
+        &self,
+        meta: &diff::Meta,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<diff::Details> {
+        let path = meta.file().rel_path_required()?;
+        self._diff_details(path, cancelled)
     }
 
-    fn _diff_details(&self, path: &Path) -> Result<diff::Details> {
+    fn _diff_details(&self, path: &Path, cancelled: &dyn Fn() -> bool) -> Result<diff::Details> {
         let head = self.head()?;
 
+        // Matched literally, so only `path` is diffed rather than everything
+        // a glob in it might match.
         let mut opts = Self::uncommitted_opts();
-        opts.pathspec(path);
+        opts.disable_pathspec_match(true).pathspec(path);
 
+        let diff = self
+            .git
+            .diff_tree_to_workdir_with_index(head.as_ref(), Some(&mut opts))?;
+        Self::details_in_unless(&diff, path, cancelled)
+    }
+
+    /// Details of the delta for `path` in `diff`.
+    fn details_in(diff: &git2::Diff, path: &Path) -> Result<diff::Details> {
+        Self::details_in_unless(diff, path, &|| false)
+    }
+
+    fn details_in_unless(
+        diff: &git2::Diff,
+        path: &Path,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<diff::Details> {
         let mut meta: Option<diff::Meta> = None;
         let mut file_cb = |delta: git2::DiffDelta<'_>, _progress| {
             if let Some(delta_path) = Self::delta_path(&delta) {
@@ -189,36 +1368,123 @@ impl Internal {
             // NOTE: If we ask to stop once we get the target lines_cb isn't
             // called, so we exit on the first subsequent delta.
 
-            meta.is_none()
+            meta.is_none() && !cancelled()
         };
 
-        let mut lines = vec![];
+        let mut hunks: Vec<diff::Hunk> = vec![];
         let mut line_cb = |delta: git2::DiffDelta<'_>,
-                           _hunk: Option<git2::DiffHunk<'_>>,
+                           hunk: Option<git2::DiffHunk<'_>>,
                            line: git2::DiffLine<'_>| {
-            if let Some(delta_path) = Self::delta_path(&delta) {
+            if let (Some(delta_path), Some(hunk)) = (Self::delta_path(&delta), hunk) {
                 if delta_path == path {
+                    if !hunks.last().map_or(false, |last| last.is_git2(&hunk)) {
+                        hunks.push(diff::Hunk::from_git2(&hunk));
+                    }
+
                     let line = diff::Line::from_git2(&line);
-                    lines.push(line);
+                    hunks.last_mut().expect("Just pushed").push(line);
                 }
             }
 
-            true
+            !cancelled()
         };
 
-        match self
-            .git
-            .diff_tree_to_workdir_with_index(head.as_ref(), Some(&mut opts))?
-            .foreach(&mut file_cb, None, None, Some(&mut line_cb))
-        {
+        match diff.foreach(&mut file_cb, None, None, Some(&mut line_cb)) {
             Ok(()) => (),
             Err(err) if err.code() == git2::ErrorCode::User => (),
             Err(err) => return Err(err.into()),
         }
+        if cancelled() {
+            return Err(Error::Cancelled);
+        }
 
         let meta = meta.ok_or_else(|| Error::PathNotFound(path.to_path_buf()))?;
 
-        Ok(diff::Details::new(meta, lines))
+        Ok(diff::Details::new(meta, hunks))
+    }
+
+    pub(crate) fn diff(
+        &self,
+        from: &Revision,
+        to: &Revision,
+        options: &DiffOptions,
+    ) -> Result<Vec<diff::Details>> {
+        use Revision::{Commit, Index, Workdir};
+
+        let mut opts = options.to_git2();
+        // libgit2 only diffs from a commit to the index to the workdir, so
+        // going the other way is the same diff reversed.
+        opts.reverse(matches!(
+            (from, to),
+            (Index | Workdir, Commit(_)) | (Workdir, Index)
+        ));
+
+        let git = &self.git;
+        let mut diff = match (from, to) {
+            (Commit(from), Commit(to)) => git.diff_tree_to_tree(
+                self.revision_tree(from)?.as_ref(),
+                self.revision_tree(to)?.as_ref(),
+                Some(&mut opts),
+            )?,
+            (Commit(rev), Index) | (Index, Commit(rev)) => git.diff_tree_to_index(
+                self.revision_tree(rev)?.as_ref(),
+                Some(&self.index()?),
+                Some(&mut opts),
+            )?,
+            (Commit(rev), Workdir) | (Workdir, Commit(rev)) => git
+                .diff_tree_to_workdir_with_index(
+                    self.revision_tree(rev)?.as_ref(),
+                    Some(&mut opts),
+                )?,
+            (Index, Workdir) | (Workdir, Index) => {
+                git.diff_index_to_workdir(Some(&self.index()?), Some(&mut opts))?
+            }
+            (Index, Index) | (Workdir, Workdir) => return Ok(vec![]),
+        };
+        if let Some(mut find) = options.find_similar() {
+            diff.find_similar(Some(&mut find))?;
+        }
+
+        Self::all_details(&diff)
+    }
+
+    /// The tree of the commit `rev` names, or `None` if it's an unborn HEAD.
+    fn revision_tree(&self, rev: &str) -> Result<Option<git2::Tree>> {
+        match self.git.revparse_single(rev) {
+            Ok(object) => Ok(Some(object.peel_to_tree()?)),
+            Err(_) if rev == "HEAD" && self.head_commit()?.is_none() => Ok(None),
+            Err(err)
+                if err.code() == git2::ErrorCode::NotFound
+                    || err.code() == git2::ErrorCode::InvalidSpec =>
+            {
+                Err(Error::RevisionNotFound(rev.to_owned()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Details of every delta in `diff`, in order. Reading each patch in turn
+    /// is much quicker than [`Self::details_in`] for every path when there
+    /// are a lot of them.
+    fn all_details(diff: &git2::Diff) -> Result<Vec<diff::Details>> {
+        let mut all = vec![];
+        for (idx, delta) in diff.deltas().enumerate() {
+            let mut hunks: Vec<diff::Hunk> = vec![];
+            // There's no patch for a binary file
+            if let Some(patch) = git2::Patch::from_diff(diff, idx)? {
+                for hunk_idx in 0..patch.num_hunks() {
+                    let (hunk, lines) = patch.hunk(hunk_idx)?;
+                    let mut hunk = diff::Hunk::from_git2(&hunk);
+                    for line_idx in 0..lines {
+                        let line = patch.line_in_hunk(hunk_idx, line_idx)?;
+                        hunk.push(diff::Line::from_git2(&line));
+                    }
+                    hunks.push(hunk);
+                }
+            }
+            all.push(diff::Details::new(diff::Meta::from_git2(&delta), hunks));
+        }
+        Ok(all)
     }
 
     fn delta_path<'a, 'b>(delta: &'a git2::DiffDelta<'b>) -> Option<&'b Path> {
@@ -239,20 +1505,558 @@ impl Internal {
         opts
     }
 
-    fn stage_file(&self, file: &File) -> Result<()> {
-        let path = file.rel_path_required()?;
-        if self.git.status_should_ignore(path)? {
-            debug!("Ignoring {:?}", file);
+    /// Make the index entry for `path` match the workdir, including its mode
+    /// and whether it exists at all. Untracked files that are ignored are
+    /// left alone, like `git add` does.
+    fn stage_file(&self, path: &Path) -> Result<index::Update> {
+        let mut index = self.index()?;
+        let tracked = index::Entry::get(&index, path).is_some();
+        let exists = self.in_workdir(path)?;
+
+        index::Update::record(&mut index, path, |index| {
+            if !exists {
+                index.remove_path(path)?;
+            } else if !tracked && self.git.status_should_ignore(path)? {
+                debug!("Ignoring {:?}", path);
+            } else {
+                index.add_path(path)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Reset the index entry for `path` to its version in HEAD, or remove it
+    /// if it isn't in HEAD, like `git reset -- <path>`. Stat information is
+    /// kept where it still describes the entry.
+    fn unstage_file(&self, path: &Path) -> Result<index::Update> {
+        let head = self.head_blob(path)?;
+        let mut index = self.index()?;
+
+        index::Update::record(&mut index, path, |index| {
+            let (id, mode) = if let Some(head) = head {
+                head
+            } else {
+                index.remove_path(path)?;
+                return Ok(());
+            };
+
+            let entry = match index::Entry::get(index, path) {
+                Some(entry) if entry.id() == id && entry.mode() == mode => return Ok(()),
+                Some(entry) if entry.id() == id => entry.with_mode(mode),
+                Some(entry) => {
+                    let size = self.git.find_blob(id)?.size();
+                    entry.with_blob(id, size).with_mode(mode)
+                }
+                None => {
+                    let size = self.git.find_blob(id)?.size();
+                    index::Entry::new_blob(path, id, mode, size)
+                }
+            };
+            index.add(&entry.to_git2())?;
+            Ok(())
+        })
+    }
+
+    /// The id and mode of `path` in HEAD, if it's there
+    pub(crate) fn head_blob(&self, path: &Path) -> Result<Option<(git2::Oid, u32)>> {
+        let head = match self.head()? {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        match head.get_path(path) {
+            #[allow(clippy::cast_sign_loss)]
+            Ok(entry) => Ok(Some((entry.id(), entry.filemode() as u32))),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn in_workdir(&self, path: &Path) -> Result<bool> {
+        match std::fs::symlink_metadata(self.path().join(path)) {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn side_details(&self, side: Side, path: &Path) -> Result<diff::Details> {
+        let mut opts = Self::staging_opts(path);
+        let diff = match side {
+            Side::Staged => {
+                let head = self.head()?;
+                self.git
+                    .diff_tree_to_index(head.as_ref(), None, Some(&mut opts))?
+            }
+            Side::Unstaged => self.git.diff_index_to_workdir(None, Some(&mut opts))?,
+        };
+        Ok(Self::details_in(&diff, path)?.on_side(side))
+    }
+
+    /// Apply `hunk` from the unstaged changes to the index.
+    fn stage_hunk(&self, path: &Path, hunk: &diff::HunkId) -> Result<index::Update> {
+        let details = self.side_details(Side::Unstaged, path)?;
+        let hunk = details
+            .hunks()
+            .iter()
+            .find(|candidate| candidate.id() == *hunk)
+            .ok_or_else(|| Error::HunkNotFound(path.to_path_buf()))?;
+
+        let base = self.index_content(path)?;
+        let content = patch::apply(&base, hunk.lines(), |_| true, patch::Direction::Forward);
+        self.stage_content(path, &content)
+    }
+
+    /// Revert `hunk` from the staged changes in the index.
+    fn unstage_hunk(&self, path: &Path, hunk: &diff::HunkId) -> Result<index::Update> {
+        let details = self.side_details(Side::Staged, path)?;
+        let hunk = details
+            .hunks()
+            .iter()
+            .find(|candidate| candidate.id() == *hunk)
+            .ok_or_else(|| Error::HunkNotFound(path.to_path_buf()))?;
+
+        let base = self.index_content(path)?;
+        let content = patch::apply(&base, hunk.lines(), |_| true, patch::Direction::Reverse);
+        self.stage_content(path, &content)
+    }
+
+    /// Apply the selected lines from the unstaged changes to the index.
+    fn stage_lines(&self, path: &Path, lines: &[diff::LineId]) -> Result<index::Update> {
+        let details = self.side_details(Side::Unstaged, path)?;
+        Self::check_lines(&details, path, lines)?;
+
+        let base = self.index_content(path)?;
+        let content = patch::apply(
+            &base,
+            details.lines(),
+            |line| lines.contains(&line.id()),
+            patch::Direction::Forward,
+        );
+        self.stage_content(path, &content)
+    }
+
+    /// Revert the selected lines from the staged changes in the index.
+    fn unstage_lines(&self, path: &Path, lines: &[diff::LineId]) -> Result<index::Update> {
+        let details = self.side_details(Side::Staged, path)?;
+        Self::check_lines(&details, path, lines)?;
+
+        let base = self.index_content(path)?;
+        let content = patch::apply(
+            &base,
+            details.lines(),
+            |line| lines.contains(&line.id()),
+            patch::Direction::Reverse,
+        );
+        self.stage_content(path, &content)
+    }
+
+    /// Check every one of `lines` is an added or deleted line in `details`.
+    fn check_lines(details: &diff::Details, path: &Path, lines: &[diff::LineId]) -> Result<()> {
+        let changed: HashSet<diff::LineId> = details
+            .lines()
+            .filter(|line| !line.is_eofnl())
+            .filter(|line| line.old_lineno().is_some() != line.new_lineno().is_some())
+            .map(diff::Line::id)
+            .collect();
+        let missing: Vec<diff::LineId> = lines
+            .iter()
+            .filter(|line| !changed.contains(line))
+            .copied()
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::LinesNotFound(path.to_path_buf(), missing))
+        }
+    }
+
+    /// Paths with uncommitted changes that match `pathspec`, with untracked
+    /// directories expanded into the files in them.
+    fn uncommitted_paths(&self, pathspec: &[&str]) -> Result<Vec<PathBuf>> {
+        let head = self.head()?;
+        let mut opts = Self::uncommitted_opts();
+        opts.recurse_untracked_dirs(true);
+        for spec in pathspec {
+            opts.pathspec(spec);
+        }
+
+        let diff = self
+            .git
+            .diff_tree_to_workdir_with_index(head.as_ref(), Some(&mut opts))?;
+        Ok(Self::paths_in(&diff))
+    }
+
+    /// Paths with staged changes that match `pathspec`
+    fn staged_paths(&self, pathspec: &[&str]) -> Result<Vec<PathBuf>> {
+        let head = self.head()?;
+        let mut opts = git2::DiffOptions::new();
+        for spec in pathspec {
+            opts.pathspec(spec);
+        }
+
+        let diff = self
+            .git
+            .diff_tree_to_index(head.as_ref(), None, Some(&mut opts))?;
+        Ok(Self::paths_in(&diff))
+    }
+
+    fn paths_in(diff: &git2::Diff) -> Vec<PathBuf> {
+        diff.deltas()
+            .filter_map(|delta| delta.new_file().path().or_else(|| delta.old_file().path()))
+            .map(Path::to_path_buf)
+            .collect()
+    }
+
+    fn staging_opts(path: &Path) -> git2::DiffOptions {
+        let mut opts = Self::uncommitted_opts();
+        opts.show_untracked_content(true)
+            .recurse_untracked_dirs(true)
+            .disable_pathspec_match(true)
+            .pathspec(path);
+        opts
+    }
+
+    /// Contents of the blob staged for `path`, or nothing if it isn't in the
+    /// index.
+    fn index_content(&self, path: &Path) -> Result<Vec<u8>> {
+        match index::Entry::get(&self.index()?, path) {
+            Some(entry) => Ok(self.git.find_blob(entry.id())?.content().to_vec()),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Stage `content` as the contents of `path`, keeping the mode of the
+    /// existing entry if there is one.
+    fn stage_content(&self, path: &Path, content: &[u8]) -> Result<index::Update> {
+        let id = self.git.blob(content)?;
+        let mut index = self.index()?;
+
+        index::Update::record(&mut index, path, |index| {
+            let entry = match index::Entry::get(index, path) {
+                Some(entry) => entry.with_blob(id, content.len()),
+                None => index::Entry::new_blob(path, id, self.workdir_mode(path)?, content.len()),
+            };
+            index.add(&entry.to_git2())?;
+            Ok(())
+        })
+    }
+
+    fn workdir_mode(&self, path: &Path) -> Result<u32> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let meta = std::fs::symlink_metadata(self.path().join(path))?;
+        let mode = if meta.file_type().is_symlink() {
+            git2::FileMode::Link
+        } else if meta.permissions().mode() & 0o111 != 0 {
+            git2::FileMode::BlobExecutable
         } else {
-            self.git.index()?.add_path(path)?;
+            git2::FileMode::Blob
+        };
+        Ok(u32::from(mode))
+    }
+
+    fn discard_file(&self, path: &Path, to: DiscardTo) -> Result<workdir::Update> {
+        let after = match to {
+            DiscardTo::Index => index::Entry::get(&self.index()?, path)
+                .map(|entry| workdir::Blob::new(entry.id(), entry.mode())),
+            DiscardTo::Head => self
+                .head_blob(path)?
+                .map(|(id, mode)| workdir::Blob::new(id, mode)),
+        };
+
+        workdir::Update::record(&self.git, self.path(), path, after)
+    }
+
+    /// Revert `hunk` from the unstaged changes in the workdir.
+    fn discard_hunk(&self, path: &Path, hunk: &diff::HunkId) -> Result<workdir::Update> {
+        let details = self.side_details(Side::Unstaged, path)?;
+        let hunk = details
+            .hunks()
+            .iter()
+            .find(|candidate| candidate.id() == *hunk)
+            .ok_or_else(|| Error::HunkNotFound(path.to_path_buf()))?;
+
+        let base = std::fs::read(self.path().join(path))?;
+        let content = patch::apply(&base, hunk.lines(), |_| true, patch::Direction::Reverse);
+        let after = workdir::Blob::new(self.git.blob(&content)?, self.workdir_mode(path)?);
+
+        workdir::Update::record(&self.git, self.path(), path, Some(after))
+    }
+
+    fn commit(&self, message: &str, options: &commit::Options) -> Result<refs::Update> {
+        let tree = self.index()?.write_tree()?;
+        let tree = self.git.find_tree(tree)?;
+        let parent = self.head_commit()?;
+
+        let parent_tree = match &parent {
+            Some(parent) => parent.tree_id(),
+            None => self.git.treebuilder(None)?.write()?,
+        };
+        if tree.id() == parent_tree && !options.allows_empty() {
+            return Err(Error::NothingToCommit);
         }
-        Ok(())
+
+        let signature = self.git.signature()?;
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        let id = self
+            .git
+            .commit(None, &signature, &signature, message, &tree, &parents)?;
+
+        let summary = message.lines().next().unwrap_or_default();
+        let log_message = match parent {
+            Some(_) => format!("commit: {}", summary),
+            None => format!("commit (initial): {}", summary),
+        };
+        let name = refs::head_name(&self.git)?;
+        refs::Update::record(&self.git, &name, Some(id), &log_message)
     }
 
-    fn unstage_file(&self, file: &File) -> Result<()> {
-        let path = file.rel_path_required()?;
-        self.git.index()?.remove_path(path)?;
-        Ok(())
+    fn amend(&self, message: Option<&str>, options: &commit::Options) -> Result<refs::Update> {
+        let head = self.rewritable_head(options)?;
+
+        let tree = self.index()?.write_tree()?;
+        let tree = self.git.find_tree(tree)?;
+
+        let parent_tree = match head.parents().next() {
+            Some(parent) => parent.tree_id(),
+            None => self.git.treebuilder(None)?.write()?,
+        };
+        if tree.id() == parent_tree && !options.allows_empty() {
+            return Err(Error::NothingToCommit);
+        }
+
+        self.rewrite_head(&head, message, &tree, "amend")
+    }
+
+    fn reword(&self, message: &str, options: &commit::Options) -> Result<refs::Update> {
+        let head = self.rewritable_head(options)?;
+        self.rewrite_head(&head, Some(message), &head.tree()?, "reword")
+    }
+
+    fn rewritable_head(&self, options: &commit::Options) -> Result<git2::Commit> {
+        let head = self.head_commit()?.ok_or(Error::UnbornHead)?;
+        if head.parent_count() > 1 && !options.allows_merge() {
+            return Err(Error::HeadIsMerge(head.id()));
+        }
+        Ok(head)
+    }
+
+    /// Replace HEAD with a commit with the same parents and author.
+    fn rewrite_head(
+        &self,
+        head: &git2::Commit,
+        message: Option<&str>,
+        tree: &git2::Tree,
+        action: &str,
+    ) -> Result<refs::Update> {
+        let committer = self.git.signature()?;
+        let id = head.amend(None, None, Some(&committer), None, message, Some(tree))?;
+
+        let new_message = message.or_else(|| head.message()).unwrap_or_default();
+        let summary = new_message.lines().next().unwrap_or_default();
+        let log_message = format!("commit ({}): {}", action, summary);
+        let name = refs::head_name(&self.git)?;
+        refs::Update::record(&self.git, &name, Some(id), &log_message)
+    }
+
+    fn create_branch(&self, name: &str, at: Option<git2::Oid>) -> Result<refs::Update> {
+        let full = branch::ref_name(name)?;
+        if self.git.find_reference(&full).is_ok() {
+            return Err(Error::BranchExists(name.to_owned()));
+        }
+        let at = match at {
+            Some(at) => self.git.find_commit(at)?.id(),
+            None => self.head_commit()?.ok_or(Error::UnbornHead)?.id(),
+        };
+
+        let log_message = format!("branch: Created from {}", at);
+        refs::Update::record(&self.git, &full, Some(at), &log_message)
+    }
+
+    /// The files `scope` covers that have changes to stash
+    fn stash_paths(&self, scope: &stash::Scope) -> Result<Vec<PathBuf>> {
+        let mut options = StatusOptions::new();
+        options.detect_renames(None);
+        match scope {
+            stash::Scope::Tracked => {
+                options.untracked(Untracked::No);
+            }
+            stash::Scope::Staged => return self.staged_paths(&[]),
+            stash::Scope::Files(files) => {
+                options.untracked(Untracked::All).literal_pathspec(true);
+                for file in files {
+                    let path = file.rel_path_required()?;
+                    options.pathspec(&path.to_string_lossy());
+                }
+            }
+        }
+
+        let paths = self
+            .uncommitted_files(&options)?
+            .iter()
+            .filter_map(|meta| meta.file().rel_path())
+            .map(Path::to_path_buf)
+            .collect();
+        Ok(paths)
+    }
+
+    /// Save `paths` as a new stash and reset them to HEAD. If `only_staged`
+    /// the workdir versions aren't stashed, and only the staged changes are
+    /// reverted in the workdir, like `git stash --staged`.
+    fn stash_push(
+        &self,
+        message: Option<&str>,
+        paths: &[PathBuf],
+        only_staged: bool,
+    ) -> Result<stash::Push> {
+        let head = self.head_commit()?.ok_or(Error::UnbornHead)?;
+        let head_tree = head.tree()?;
+        let staged = stash::tree_from_index(&self.git, &head_tree, &self.index()?, paths)?;
+        let unstaged = stash::tree_from_workdir(&self.git, &head_tree, self.path(), paths)?;
+
+        let kept = if only_staged {
+            Some(self.merge_paths(&staged, &unstaged, &head_tree, paths)?)
+        } else {
+            None
+        };
+        let id = stash::create(
+            &self.git,
+            &head,
+            &staged,
+            if only_staged { &staged } else { &unstaged },
+            message,
+        )?;
+
+        let mut index = vec![];
+        let mut workdir = vec![];
+        for (n, path) in paths.iter().enumerate() {
+            index.push(self.unstage_file(path)?);
+            workdir.push(match &kept {
+                Some(kept) => {
+                    let after = kept[n].map(|(id, mode)| workdir::Blob::new(id, mode));
+                    workdir::Update::record(&self.git, self.path(), path, after)?
+                }
+                None => self.discard_file(path, DiscardTo::Head)?,
+            });
+        }
+        stash::Push::record(&self.git, id, index, workdir)
+    }
+
+    /// What each of `paths` becomes when the changes from `ancestor` to
+    /// `theirs` are merged into `ours`, or [`Error::StashConflicts`] if any
+    /// of them can't be.
+    fn merge_paths(
+        &self,
+        ancestor: &git2::Tree,
+        ours: &git2::Tree,
+        theirs: &git2::Tree,
+        paths: &[PathBuf],
+    ) -> Result<Vec<Option<(git2::Oid, u32)>>> {
+        let mut opts = git2::MergeOptions::new();
+        opts.find_renames(false);
+        let merged = self.git.merge_trees(ancestor, ours, theirs, Some(&opts))?;
+
+        if merged.has_conflicts() {
+            let mut conflicts = vec![];
+            for conflict in merged.conflicts()? {
+                let conflict = conflict?;
+                if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+                    conflicts.push(PathBuf::from(OsStr::from_bytes(&entry.path)));
+                }
+            }
+            return Err(Error::StashConflicts(conflicts));
+        }
+
+        let blobs = paths
+            .iter()
+            .map(|path| merged.get_path(path, 0).map(|entry| (entry.id, entry.mode)))
+            .collect();
+        Ok(blobs)
+    }
+
+    /// Merge the staged and unstaged changes to `paths` in the stash `id`
+    /// into the index and workdir, like `git stash apply --index`.
+    fn stash_apply(&self, id: git2::Oid, paths: &[PathBuf]) -> Result<stash::Apply> {
+        let trees = stash::Trees::of(&self.git, id)?;
+        let mut index = self.index()?;
+
+        // Only `paths` are read back, so the rest of each tree can be anything
+        // as long as it's the same in all three.
+        let current = stash::tree_from_index(&self.git, &trees.base, &index, paths)?;
+        let staged = self.merge_paths(&trees.base, &current, &trees.staged, paths)?;
+        let current = stash::tree_from_workdir(&self.git, &trees.base, self.path(), paths)?;
+        let unstaged = self.merge_paths(&trees.base, &current, &trees.unstaged, paths)?;
+
+        let mut index_updates = vec![];
+        let mut workdir_updates = vec![];
+        for (path, (staged, unstaged)) in paths.iter().zip(staged.into_iter().zip(unstaged)) {
+            index_updates.push(index::Update::record(&mut index, path, |index| {
+                match staged {
+                    Some((id, mode)) => {
+                        let size = self.git.find_blob(id)?.size();
+                        index.add(&index::Entry::new_blob(path, id, mode, size).to_git2())?;
+                    }
+                    None => index.remove_path(path)?,
+                }
+                Ok(())
+            })?);
+
+            let unstaged = unstaged.map(|(id, mode)| workdir::Blob::new(id, mode));
+            workdir_updates.push(workdir::Update::record(
+                &self.git,
+                self.path(),
+                path,
+                unstaged,
+            )?);
+        }
+        Ok(stash::Apply::new(index_updates, workdir_updates))
+    }
+
+    fn fingerprint(&self, change: &Change) -> Result<Fingerprint> {
+        self.fingerprint_paths(&change.workdir_paths())
+    }
+
+    fn fingerprint_paths(&self, paths: &[&Path]) -> Result<Fingerprint> {
+        Fingerprint::take(&self.git, &self.index()?, self.path(), paths)
+    }
+
+    /// Also fingerprint the paths `change` touches, if they aren't already.
+    fn add_to_fingerprint(&self, fingerprint: &mut Fingerprint, change: &Change) -> Result<()> {
+        fingerprint.add_paths(self.path(), &change.workdir_paths())
+    }
+
+    /// Check the repository is in the state it was last seen in by `change`.
+    fn check(&self, change: &Change, expected: Option<&Fingerprint>) -> Result<()> {
+        let expected = match expected {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+
+        match self.fingerprint(change)?.diverged_from(expected) {
+            Some(divergence) => Err(Error::HistoryDiverged(divergence)),
+            None => Ok(()),
+        }
+    }
+
+    /// Apply an update if we already know what it does, otherwise compute it
+    /// for the first time.
+    fn apply_update<U, F>(&self, update: &mut Option<U>, compute: F) -> Result<()>
+    where
+        U: Replay,
+        F: FnOnce(&Self) -> Result<U>,
+    {
+        if let Some(update) = update {
+            update.apply(self)
+        } else {
+            *update = Some(compute(self)?);
+            Ok(())
+        }
+    }
+
+    fn revert_update<U: Replay>(&self, update: Option<&U>) -> Result<()> {
+        update
+            .expect("Changes are applied before they're undone")
+            .revert(self)
     }
 }
 
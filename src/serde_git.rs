@@ -0,0 +1,42 @@
+//! Serde helpers for git2 types, for use with `#[serde(with = "...")]`.
+
+pub(crate) mod oid {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        id: &git2::Oid,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&id.to_string())
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<git2::Oid, D::Error> {
+        let id = String::deserialize(deserializer)?;
+        git2::Oid::from_str(&id).map_err(D::Error::custom)
+    }
+}
+
+pub(crate) mod option_oid {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        id: &Option<git2::Oid>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match id {
+            Some(id) => serializer.serialize_some(&id.to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<git2::Oid>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(id) => git2::Oid::from_str(&id).map(Some).map_err(D::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
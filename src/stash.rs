@@ -0,0 +1,452 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{index, serde_git, workdir, Error, RepoFile, Result};
+#[allow(unused)]
+use tracing::{debug, error, info, instrument, span, warn};
+
+const REF: &str = "refs/stash";
+
+/// A set of changes put aside with [`crate::Repo::stash_push`]
+#[derive(Debug, Clone)]
+pub struct Stash {
+    index: usize,
+    id: git2::Oid,
+    message: String,
+}
+
+impl Stash {
+    /// Position in the list of stashes, newest first, as in `stash@{0}`
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The commit the stash is saved as
+    pub fn id(&self) -> git2::Oid {
+        self.id
+    }
+
+    /// Such as "On main: Half-finished refactor"
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+/// What to stash
+#[derive(Debug, Clone)]
+pub enum Scope {
+    /// Every tracked file with changes, like `git stash push`
+    Tracked,
+    /// Only the staged changes, like `git stash --staged`. Unstaged changes
+    /// are left in the workdir.
+    Staged,
+    /// Just these files, including any that are untracked
+    Files(Vec<RepoFile>),
+}
+
+/// Every stash, newest first.
+pub(crate) fn list(git: &git2::Repository) -> Result<Vec<Stash>> {
+    let reflog = git.reflog(REF)?;
+    let stashes = reflog
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| Stash {
+            index,
+            id: entry.id_new(),
+            message: entry.message().unwrap_or_default().to_owned(),
+        })
+        .collect();
+    Ok(stashes)
+}
+
+/// The commit for the stash at `index`
+pub(crate) fn get(git: &git2::Repository, index: usize) -> Result<git2::Oid> {
+    ids(git)?
+        .get(index)
+        .copied()
+        .ok_or(Error::StashNotFound(index))
+}
+
+fn ids(git: &git2::Repository) -> Result<Vec<git2::Oid>> {
+    Ok(git
+        .reflog(REF)?
+        .iter()
+        .map(|entry| entry.id_new())
+        .collect())
+}
+
+/// An entry in the stash reflog, kept so it can be put back exactly as it was
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    #[serde(with = "serde_git::oid")]
+    id: git2::Oid,
+    message: String,
+    name: String,
+    email: String,
+    /// Seconds since the epoch
+    time: i64,
+    /// Minutes east of UTC
+    offset: i32,
+}
+
+impl Entry {
+    /// A new entry for the stash `id`, as `git stash` would write it
+    fn new(git: &git2::Repository, id: git2::Oid) -> Result<Self> {
+        let commit = git.find_commit(id)?;
+        let signature = git.signature()?;
+        Ok(Self::from_parts(
+            id,
+            commit.summary().unwrap_or_default(),
+            &signature,
+        ))
+    }
+
+    fn from_git2(entry: &git2::ReflogEntry) -> Self {
+        Self::from_parts(
+            entry.id_new(),
+            entry.message().unwrap_or_default(),
+            &entry.committer(),
+        )
+    }
+
+    fn from_parts(id: git2::Oid, message: &str, committer: &git2::Signature) -> Self {
+        Self {
+            id,
+            message: message.to_owned(),
+            name: String::from_utf8_lossy(committer.name_bytes()).into_owned(),
+            email: String::from_utf8_lossy(committer.email_bytes()).into_owned(),
+            time: committer.when().seconds(),
+            offset: committer.when().offset_minutes(),
+        }
+    }
+
+    /// Add as the newest entry in `reflog`
+    fn append_to(&self, reflog: &mut git2::Reflog) -> Result<()> {
+        let when = git2::Time::new(self.time, self.offset);
+        let committer = git2::Signature::new(&self.name, &self.email, &when)?;
+        reflog.append(self.id, &committer, Some(&self.message))?;
+        Ok(())
+    }
+}
+
+/// Save `reflog` once it's been changed, and point the stash ref at the
+/// newest entry left, or delete both if there aren't any.
+fn write(git: &git2::Repository, reflog: &mut git2::Reflog) -> Result<()> {
+    let newest = if let Some(newest) = reflog.get(0) {
+        newest.id_new()
+    } else {
+        match git.find_reference(REF) {
+            Ok(mut reference) => reference.delete()?,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+        git.reflog_delete(REF)?;
+        return Ok(());
+    };
+
+    reflog.write()?;
+    git.reference(REF, newest, true, "idgit: update stash")?;
+    // Moving the ref adds to its reflog, so write ours again over that, as
+    // `git stash drop` does
+    reflog.write()?;
+    Ok(())
+}
+
+/// Put `entry` back in the list at `index`.
+fn insert(git: &git2::Repository, index: usize, entry: &Entry) -> Result<()> {
+    let mut reflog = git.reflog(REF)?;
+    let index = index.min(reflog.len());
+
+    // Entries can only be added as the newest, so take off those that should
+    // be newer and add them back after, unchanged.
+    let newer: Vec<Entry> = reflog
+        .iter()
+        .take(index)
+        .map(|entry| Entry::from_git2(&entry))
+        .collect();
+    for _ in 0..index {
+        reflog.remove(0, false)?;
+    }
+    entry.append_to(&mut reflog)?;
+    for newer in newer.iter().rev() {
+        newer.append_to(&mut reflog)?;
+    }
+    write(git, &mut reflog)
+}
+
+/// Remove the stash `id`, which is expected to be at `index`, leaving the
+/// rest of the list as it was.
+fn remove(git: &git2::Repository, index: usize, id: git2::Oid) -> Result<Entry> {
+    let mut reflog = git.reflog(REF)?;
+    let index = match reflog.get(index) {
+        Some(entry) if entry.id_new() == id => index,
+        _ => reflog
+            .iter()
+            .position(|entry| entry.id_new() == id)
+            .ok_or(Error::StashNotFound(index))?,
+    };
+
+    let entry = Entry::from_git2(&reflog.get(index).expect("Just found it"));
+    reflog.remove(index, true)?;
+    write(git, &mut reflog)?;
+    Ok(entry)
+}
+
+/// `base` with `paths` as they are in `index`
+pub(crate) fn tree_from_index<'r>(
+    git: &'r git2::Repository,
+    base: &git2::Tree,
+    index: &git2::Index,
+    paths: &[PathBuf],
+) -> Result<git2::Tree<'r>> {
+    let mut tree = git2::Index::new()?;
+    tree.read_tree(base)?;
+    for path in paths {
+        match index::Entry::get(index, path) {
+            Some(entry) => tree.add(&entry.to_git2())?,
+            None => remove_path(&mut tree, path)?,
+        }
+    }
+    Ok(git.find_tree(tree.write_tree_to(git)?)?)
+}
+
+/// `base` with `paths` as they are in the workdir, saving their contents in
+/// the object database. Untracked files are included, so git sees them as
+/// added.
+pub(crate) fn tree_from_workdir<'r>(
+    git: &'r git2::Repository,
+    base: &git2::Tree,
+    workdir: &Path,
+    paths: &[PathBuf],
+) -> Result<git2::Tree<'r>> {
+    let mut tree = git2::Index::new()?;
+    tree.read_tree(base)?;
+    for path in paths {
+        match workdir::Blob::save(git, &workdir.join(path))? {
+            Some(blob) => {
+                let size = git.find_blob(blob.id())?.size();
+                let entry = index::Entry::new_blob(path, blob.id(), blob.mode(), size);
+                tree.add(&entry.to_git2())?;
+            }
+            None => remove_path(&mut tree, path)?,
+        }
+    }
+    Ok(git.find_tree(tree.write_tree_to(git)?)?)
+}
+
+/// Save `staged` and `unstaged`, usually from [`tree_from_index`] and
+/// [`tree_from_workdir`], as a stash commit on `head` in the same form as
+/// `git stash`, without changing anything else.
+pub(crate) fn create(
+    git: &git2::Repository,
+    head: &git2::Commit,
+    staged: &git2::Tree,
+    unstaged: &git2::Tree,
+    message: Option<&str>,
+) -> Result<git2::Oid> {
+    let branch = git
+        .head()?
+        .shorthand()
+        .map_or_else(|| "(no branch)".to_owned(), str::to_owned);
+    let short = head.as_object().short_id()?;
+    let short = short.as_str().unwrap_or_default();
+    let summary = head.summary().unwrap_or_default();
+    let message = match message {
+        Some(message) => format!("On {}: {}", branch, message),
+        None => format!("WIP on {}: {} {}", branch, short, summary),
+    };
+
+    let signature = git.signature()?;
+    let index_message = format!("index on {}: {} {}", branch, short, summary);
+    let index_commit = git.commit(
+        None,
+        &signature,
+        &signature,
+        &index_message,
+        staged,
+        &[head],
+    )?;
+    let index_commit = git.find_commit(index_commit)?;
+    let id = git.commit(
+        None,
+        &signature,
+        &signature,
+        &message,
+        unstaged,
+        &[head, &index_commit],
+    )?;
+    Ok(id)
+}
+
+fn remove_path(index: &mut git2::Index, path: &Path) -> Result<()> {
+    match index.remove_path(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The commit a stash was made on, and the trees of what was staged and what
+/// was in the workdir.
+pub(crate) struct Trees<'r> {
+    pub(crate) base: git2::Tree<'r>,
+    pub(crate) staged: git2::Tree<'r>,
+    pub(crate) unstaged: git2::Tree<'r>,
+}
+
+impl<'r> Trees<'r> {
+    pub(crate) fn of(git: &'r git2::Repository, id: git2::Oid) -> Result<Self> {
+        let commit = git.find_commit(id)?;
+        Ok(Self {
+            base: commit.parent(0)?.tree()?,
+            staged: commit.parent(1)?.tree()?,
+            unstaged: commit.tree()?,
+        })
+    }
+
+    /// Every path the stash changes, in the index or the workdir
+    pub(crate) fn paths(&self, git: &git2::Repository) -> Result<Vec<PathBuf>> {
+        let mut paths: Vec<PathBuf> = vec![];
+        for tree in &[&self.staged, &self.unstaged] {
+            let diff = git.diff_tree_to_tree(Some(&self.base), Some(tree), None)?;
+            for delta in diff.deltas() {
+                for file in &[delta.old_file(), delta.new_file()] {
+                    if let Some(path) = file.path() {
+                        if !paths.iter().any(|known| known == path) {
+                            paths.push(path.to_path_buf());
+                        }
+                    }
+                }
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+/// A new stash being added to the top of the list, along with what it took
+/// out of the index and workdir.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Push {
+    entry: Entry,
+    index: Vec<index::Update>,
+    workdir: Vec<workdir::Update>,
+}
+
+impl Push {
+    /// Add the stash `id` to the list, once `index` and `workdir` have
+    /// already been applied.
+    pub(crate) fn record(
+        git: &git2::Repository,
+        id: git2::Oid,
+        index: Vec<index::Update>,
+        workdir: Vec<workdir::Update>,
+    ) -> Result<Self> {
+        let entry = Entry::new(git, id)?;
+        insert(git, 0, &entry)?;
+        Ok(Self {
+            entry,
+            index,
+            workdir,
+        })
+    }
+
+    pub(crate) fn apply(
+        &self,
+        git: &git2::Repository,
+        index: &mut git2::Index,
+        workdir: &Path,
+    ) -> Result<()> {
+        for update in &self.index {
+            update.apply(index)?;
+        }
+        for update in &self.workdir {
+            update.apply(git, workdir)?;
+        }
+        insert(git, 0, &self.entry)
+    }
+
+    pub(crate) fn revert(
+        &self,
+        git: &git2::Repository,
+        index: &mut git2::Index,
+        workdir: &Path,
+    ) -> Result<()> {
+        remove(git, 0, self.entry.id)?;
+        for update in self.workdir.iter().rev() {
+            update.revert(git, workdir)?;
+        }
+        for update in self.index.iter().rev() {
+            update.revert(index)?;
+        }
+        Ok(())
+    }
+}
+
+/// A stash being applied to the index and workdir
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Apply {
+    index: Vec<index::Update>,
+    workdir: Vec<workdir::Update>,
+}
+
+impl Apply {
+    pub(crate) fn new(index: Vec<index::Update>, workdir: Vec<workdir::Update>) -> Self {
+        Self { index, workdir }
+    }
+
+    pub(crate) fn apply(
+        &self,
+        git: &git2::Repository,
+        index: &mut git2::Index,
+        workdir: &Path,
+    ) -> Result<()> {
+        for update in &self.index {
+            update.apply(index)?;
+        }
+        for update in &self.workdir {
+            update.apply(git, workdir)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn revert(
+        &self,
+        git: &git2::Repository,
+        index: &mut git2::Index,
+        workdir: &Path,
+    ) -> Result<()> {
+        for update in self.workdir.iter().rev() {
+            update.revert(git, workdir)?;
+        }
+        for update in self.index.iter().rev() {
+            update.revert(index)?;
+        }
+        Ok(())
+    }
+}
+
+/// A stash being removed from the list. Its entry is kept so undoing can put
+/// it back where it was.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Remove {
+    index: usize,
+    entry: Entry,
+}
+
+impl Remove {
+    pub(crate) fn record(git: &git2::Repository, index: usize) -> Result<Self> {
+        let entry = remove(git, index, get(git, index)?)?;
+        Ok(Self { index, entry })
+    }
+
+    pub(crate) fn apply(&self, git: &git2::Repository) -> Result<()> {
+        remove(git, self.index, self.entry.id)?;
+        Ok(())
+    }
+
+    pub(crate) fn revert(&self, git: &git2::Repository) -> Result<()> {
+        insert(git, self.index, &self.entry)
+    }
+}
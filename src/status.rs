@@ -0,0 +1,167 @@
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use crate::Meta;
+#[allow(unused)]
+use tracing::{debug, error, info, instrument, span, warn};
+
+/// The staged and unstaged changes to a single path, like a line of
+/// `git status --porcelain=v2`.
+#[derive(Debug, Clone)]
+pub struct Status {
+    path: PathBuf,
+    staged: Option<Meta>,
+    unstaged: Option<Meta>,
+}
+
+impl Status {
+    pub(crate) fn from_git2(from: &git2::StatusEntry) -> Self {
+        Self {
+            path: PathBuf::from(OsStr::from_bytes(from.path_bytes())),
+            staged: from.head_to_index().map(|delta| Meta::from_git2(&delta)),
+            unstaged: from.index_to_workdir().map(|delta| Meta::from_git2(&delta)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Changes from HEAD to the index, if any
+    pub fn staged(&self) -> Option<&Meta> {
+        self.staged.as_ref()
+    }
+
+    /// Changes from the index to the workdir, if any
+    pub fn unstaged(&self) -> Option<&Meta> {
+        self.unstaged.as_ref()
+    }
+}
+
+/// Options for [`crate::Repo::uncommitted_files`]. The defaults match
+/// `git status`.
+#[derive(Debug, Clone)]
+pub struct Options {
+    ignored: bool,
+    untracked: Untracked,
+    renames: Option<u16>,
+    copies: Option<u16>,
+    ignore_submodules: bool,
+    pathspec: Vec<String>,
+    literal_pathspec: bool,
+}
+
+/// Which untracked files to list, like `git status --untracked-files`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Untracked {
+    /// Leave untracked files out
+    No,
+    /// List untracked files, with untracked directories listed as a whole
+    Normal,
+    /// List every untracked file, including those in untracked directories
+    All,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            ignored: false,
+            untracked: Untracked::Normal,
+            renames: Some(50),
+            copies: None,
+            ignore_submodules: false,
+            pathspec: vec![],
+            literal_pathspec: false,
+        }
+    }
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// List ignored files too
+    pub fn include_ignored(&mut self, include: bool) -> &mut Self {
+        self.ignored = include;
+        self
+    }
+
+    pub fn untracked(&mut self, untracked: Untracked) -> &mut Self {
+        self.untracked = untracked;
+        self
+    }
+
+    /// Detect renames of files at least `threshold` percent similar, or not at
+    /// all if `None`
+    pub fn detect_renames(&mut self, threshold: Option<u16>) -> &mut Self {
+        self.renames = threshold;
+        self
+    }
+
+    /// Detect copies of files at least `threshold` percent similar, or not at
+    /// all if `None`. Only modified files are considered as sources, as with
+    /// `git diff -C`.
+    pub fn detect_copies(&mut self, threshold: Option<u16>) -> &mut Self {
+        self.copies = threshold;
+        self
+    }
+
+    /// Treat every submodule as unmodified. Otherwise each submodule's
+    /// `ignore` setting in `.gitmodules` or the config applies.
+    pub fn ignore_submodules(&mut self, ignore: bool) -> &mut Self {
+        self.ignore_submodules = ignore;
+        self
+    }
+
+    /// Only list files matching `spec`, as in `git status -- <pathspec>`.
+    /// Can be given more than once to list files matching any of them.
+    pub fn pathspec(&mut self, spec: &str) -> &mut Self {
+        self.pathspec.push(spec.to_owned());
+        self
+    }
+
+    /// Match each pathspec as an exact path, or a directory to list the
+    /// files in, rather than as a glob
+    pub fn literal_pathspec(&mut self, literal: bool) -> &mut Self {
+        self.literal_pathspec = literal;
+        self
+    }
+
+    /// Everything but [`Self::pathspecs`], which are diffed one at a time
+    pub(crate) fn to_git2(&self) -> git2::DiffOptions {
+        let mut opts = git2::DiffOptions::new();
+        opts.include_typechange(true)
+            .include_unmodified(false)
+            .include_unreadable(true)
+            .include_ignored(self.ignored)
+            .include_untracked(self.untracked != Untracked::No)
+            .recurse_untracked_dirs(self.untracked == Untracked::All)
+            .ignore_submodules(self.ignore_submodules)
+            .disable_pathspec_match(self.literal_pathspec);
+        opts
+    }
+
+    pub(crate) fn pathspecs(&self) -> &[String] {
+        &self.pathspec
+    }
+
+    /// How to find renames and copies, if at all
+    pub(crate) fn find_similar(&self) -> Option<git2::DiffFindOptions> {
+        if self.renames.is_none() && self.copies.is_none() {
+            return None;
+        }
+
+        let mut opts = git2::DiffFindOptions::new();
+        if let Some(threshold) = self.renames {
+            opts.renames(true).rename_threshold(threshold);
+        }
+        if let Some(threshold) = self.copies {
+            opts.copies(true).copy_threshold(threshold);
+        }
+        Some(opts)
+    }
+}
@@ -0,0 +1,362 @@
+mod diff;
+mod history;
+mod log;
+mod status;
+
+use std::{io, sync::mpsc, thread};
+
+use idgit::{LogOptions, LogOrder, Meta, Repo, StatusOptions, Worker, WorkerResponse};
+use termion::{event::Key, input::TermRead, raw::IntoRawMode, screen::AlternateScreen};
+use tui::{
+    backend::{Backend, TermionBackend},
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, Paragraph},
+    Frame, Terminal,
+};
+
+/// How many commits the log pane reads. Enough to see what's been going on
+/// recently without reading the whole history of a large repository.
+const LOG_LIMIT: usize = 1000;
+
+pub(crate) type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Take over the terminal and run the app until the user quits.
+pub(crate) fn run(repo: Repo) -> Result<()> {
+    // Dropping these puts the terminal back how it was, including on panic.
+    let stdout = io::stdout().into_raw_mode()?;
+    let stdout = AlternateScreen::from(stdout);
+    let mut terminal = Terminal::new(TermionBackend::new(stdout))?;
+    terminal.hide_cursor()?;
+
+    let (tx, events) = mpsc::channel();
+    watch(&repo, tx.clone());
+    let worker = repo.worker(move |response| {
+        let _ = tx.send(Event::Worked(response));
+    })?;
+    let mut app = App::new(repo, worker);
+    app.refresh();
+
+    terminal.draw(|f| app.draw(f))?;
+    for event in events {
+        match event {
+            Event::Key(key) => {
+                if app.handle_key(key?) == Flow::Quit {
+                    break;
+                }
+            }
+            Event::Changed => app.refresh(),
+            Event::Worked(response) => app.handle_response(response),
+            Event::WatchFailed(err) => app.message = Some(format!("Error: {}", err)),
+        }
+        terminal.draw(|f| app.draw(f))?;
+    }
+
+    terminal.show_cursor()?;
+    Ok(())
+}
+
+enum Event {
+    Key(io::Result<Key>),
+    /// Something changed the repository, maybe outside idgit
+    Changed,
+    /// Something went wrong watching for changes
+    WatchFailed(idgit::Error),
+    /// The background worker answered
+    Worked(WorkerResponse),
+}
+
+/// Send key presses and changes to the repository to `tx`, as they happen
+fn watch(repo: &Repo, tx: mpsc::Sender<Event>) {
+    let keys = tx.clone();
+    thread::spawn(move || {
+        for key in io::stdin().keys() {
+            if keys.send(Event::Key(key)).is_err() {
+                break;
+            }
+        }
+    });
+
+    match repo.subscribe() {
+        Ok(subscription) => {
+            thread::spawn(move || {
+                for status in subscription.iter() {
+                    let event = match status {
+                        Ok(_) => Event::Changed,
+                        Err(err) => Event::WatchFailed(err),
+                    };
+                    if tx.send(event).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        Err(err) => {
+            let _ = tx.send(Event::WatchFailed(err));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Continue,
+    Quit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    Status,
+    Diff,
+    History,
+}
+
+struct App {
+    repo: Repo,
+    /// Works out the status and diffs, which can take a while in a large
+    /// repository
+    worker: Worker,
+    /// If the status pane lists ignored files
+    show_ignored: bool,
+    /// If the log is shown in place of the diff
+    show_log: bool,
+    focus: Focus,
+    status: status::Pane,
+    diff: diff::Pane,
+    history: history::Pane,
+    log: log::Pane,
+    /// The result of the last thing the user did, shown at the bottom
+    message: Option<String>,
+}
+
+impl App {
+    fn new(repo: Repo, worker: Worker) -> Self {
+        Self {
+            repo,
+            worker,
+            show_ignored: false,
+            show_log: false,
+            focus: Focus::Status,
+            status: status::Pane::new(),
+            diff: diff::Pane::new(),
+            history: history::Pane::new(),
+            log: log::Pane::new(),
+            message: None,
+        }
+    }
+
+    fn handle_key(&mut self, key: Key) -> Flow {
+        match key {
+            Key::Char('q') | Key::Esc | Key::Ctrl('c') => return Flow::Quit,
+            Key::Char('\t') => {
+                self.focus = match self.focus {
+                    Focus::Status => Focus::Diff,
+                    Focus::Diff => Focus::History,
+                    Focus::History => Focus::Status,
+                }
+            }
+            Key::BackTab => {
+                self.focus = match self.focus {
+                    Focus::Status => Focus::History,
+                    Focus::Diff => Focus::Status,
+                    Focus::History => Focus::Diff,
+                }
+            }
+            Key::Char('j') | Key::Down => self.move_by(1),
+            Key::Char('k') | Key::Up => self.move_by(-1),
+            Key::PageDown => self.move_by(10),
+            Key::PageUp => self.move_by(-10),
+            Key::Char('s') => self.act("Staged", Repo::stage),
+            Key::Char('u') => self.act("Unstaged", Repo::unstage),
+            Key::Char('S') => self.act_on_group("Staged", Repo::stage),
+            Key::Char('U') => self.act_on_group("Unstaged", Repo::unstage),
+            Key::Char('a') => {
+                let result = self.repo.stage_all();
+                self.report(result, "Staged all files");
+            }
+            Key::Char('A') => {
+                let result = self.repo.unstage_all();
+                self.report(result, "Unstaged all files");
+            }
+            Key::Char('z') => {
+                let result = self.repo.undo();
+                self.report(result, "Undone");
+            }
+            Key::Char('Z') | Key::Ctrl('r') => {
+                let result = self.repo.redo();
+                self.report(result, "Redone");
+            }
+            Key::Char('\n') if self.focus == Focus::History => {
+                let (branch, position) = self.history.selected();
+                let result = self.repo.goto(branch, position);
+                self.report(result, "Jumped");
+            }
+            Key::Char('i') => {
+                self.show_ignored = !self.show_ignored;
+                self.refresh();
+            }
+            Key::Char('l') => {
+                self.show_log = !self.show_log;
+                self.refresh();
+            }
+            Key::Char('r') => {
+                self.message = None;
+                self.refresh();
+            }
+            _ => (),
+        }
+        Flow::Continue
+    }
+
+    fn move_by(&mut self, delta: isize) {
+        match self.focus {
+            Focus::Status => {
+                self.status.move_by(delta);
+                self.load_diff();
+            }
+            Focus::Diff if self.show_log => self.log.move_by(delta),
+            Focus::Diff => self.diff.scroll_by(delta),
+            Focus::History => self.history.move_by(delta),
+        }
+    }
+
+    /// Do something to the selected file
+    fn act<F>(&mut self, done: &str, f: F)
+    where
+        F: FnOnce(&mut Repo, &Meta) -> idgit::Result<()>,
+    {
+        let meta = match self.status.selected() {
+            Some(meta) => meta.clone(),
+            None => return,
+        };
+        let result = f(&mut self.repo, &meta);
+        let done = format!("{} {}", done, status::display_meta(&meta));
+        self.report(result, &done);
+    }
+
+    /// Do something to every file in the same group as the selected one, as a
+    /// single step in the history
+    fn act_on_group<F>(&mut self, done: &str, f: F)
+    where
+        F: Fn(&mut Repo, &Meta) -> idgit::Result<()>,
+    {
+        let (heading, files) = match self.status.selected_group() {
+            Some(group) => group,
+            None => return,
+        };
+        let done = format!("{} all {} files", done, heading.to_lowercase());
+        let result = self.repo.transaction(&done, |repo| {
+            files.iter().try_for_each(|file| f(repo, file))
+        });
+        self.report(result, &done);
+    }
+
+    fn report(&mut self, result: idgit::Result<()>, done: &str) {
+        self.message = Some(match result {
+            Ok(()) => done.to_owned(),
+            Err(err) => format!("Error: {}", err),
+        });
+        self.refresh();
+    }
+
+    /// Re-read everything from the repository, as anything may have changed.
+    /// The files and diff are shown once the worker has them.
+    fn refresh(&mut self) {
+        self.worker
+            .files(StatusOptions::new().include_ignored(self.show_ignored));
+        self.history.set(&self.repo.history_branches());
+        if self.show_log {
+            self.load_log();
+        }
+    }
+
+    fn load_log(&mut self) {
+        self.worker.log(
+            LogOptions::new().all(true).order(LogOrder::Topological),
+            LOG_LIMIT,
+        );
+    }
+
+    fn load_diff(&mut self) {
+        match self.status.selected() {
+            Some(meta) => self.worker.details(meta),
+            None => self.diff.clear(),
+        }
+    }
+
+    fn handle_response(&mut self, response: WorkerResponse) {
+        match response {
+            WorkerResponse::Files(Ok(files)) => {
+                self.status.set(files);
+                self.load_diff();
+            }
+            WorkerResponse::Files(Err(err)) | WorkerResponse::Log(Err(err)) => {
+                self.message = Some(format!("Error: {}", err));
+            }
+            WorkerResponse::Details(meta, details) => {
+                // The selection may have moved on since this was asked for
+                let selected = self.status.selected().map(|meta| meta.file().rel_path());
+                if selected != Some(meta.file().rel_path()) {
+                    return;
+                }
+                match details {
+                    Ok(details) => self.diff.set(details),
+                    Err(err) => {
+                        self.diff.clear();
+                        self.message = Some(format!("Error: {}", err));
+                    }
+                }
+            }
+            WorkerResponse::Log(Ok(commits)) => self.log.set(commits),
+        }
+    }
+
+    fn draw<B: Backend>(&mut self, f: &mut Frame<B>) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(1), Constraint::Length(1)].as_ref())
+            .split(f.size());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(35), Constraint::Percentage(65)].as_ref())
+            .split(rows[0]);
+        let left = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Percentage(60), Constraint::Percentage(40)].as_ref())
+            .split(columns[0]);
+
+        self.status.draw(f, left[0], self.focus == Focus::Status);
+        self.history.draw(f, left[1], self.focus == Focus::History);
+        if self.show_log {
+            self.log.draw(f, columns[1], self.focus == Focus::Diff);
+        } else {
+            self.diff.draw(f, columns[1], self.focus == Focus::Diff);
+        }
+        self.draw_footer(f, rows[1]);
+    }
+
+    fn draw_footer<B: Backend>(&self, f: &mut Frame<B>, area: Rect) {
+        let text = if let Some(message) = &self.message {
+            Spans::from(Span::raw(message.as_str()))
+        } else {
+            Spans::from(Span::styled(
+                "s/S/a stage file/group/all  u/U/A unstage file/group/all  z undo  Z redo  enter jump to history  i show/hide ignored  l show/hide log  r refresh  tab switch pane  q quit",
+                Style::default().fg(Color::DarkGray),
+            ))
+        };
+        f.render_widget(Paragraph::new(text), area);
+    }
+}
+
+/// The border around a pane, highlighted if it has focus
+fn pane_block(title: &str, focused: bool) -> Block<'_> {
+    let style = if focused {
+        Style::default().fg(Color::Yellow)
+    } else {
+        Style::default()
+    };
+    Block::default()
+        .borders(Borders::ALL)
+        .border_style(style)
+        .title(title)
+}
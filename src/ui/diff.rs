@@ -0,0 +1,99 @@
+use std::convert::TryFrom;
+
+use idgit::diff::{Details, Line};
+use tui::{
+    backend::Backend,
+    layout::Rect,
+    style::{Color, Style},
+    text::{Span, Spans},
+    widgets::Paragraph,
+    Frame,
+};
+
+use super::pane_block;
+
+/// Shows the changes to the selected file.
+pub(super) struct Pane {
+    details: Option<Details>,
+    scroll: u16,
+}
+
+impl Pane {
+    pub(super) fn new() -> Self {
+        Self {
+            details: None,
+            scroll: 0,
+        }
+    }
+
+    pub(super) fn set(&mut self, details: Details) {
+        self.details = Some(details);
+        self.scroll = 0;
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.details = None;
+        self.scroll = 0;
+    }
+
+    pub(super) fn scroll_by(&mut self, delta: isize) {
+        let height = self
+            .details
+            .as_ref()
+            .map_or(0, |details| details.hunks().len() + details.lines().count());
+        let max = isize::try_from(height.saturating_sub(1)).unwrap_or(isize::MAX);
+        let scroll = isize::try_from(self.scroll).unwrap_or(isize::MAX);
+        let scrolled = (scroll + delta).max(0).min(max);
+        self.scroll = u16::try_from(scrolled).unwrap_or(u16::MAX);
+    }
+
+    pub(super) fn draw<B: Backend>(&self, f: &mut Frame<B>, area: Rect, focused: bool) {
+        let text = match &self.details {
+            Some(details) if !details.hunks().is_empty() => render(details),
+            Some(_) => vec![Spans::from("No changes to show")],
+            None => vec![],
+        };
+
+        let paragraph = Paragraph::new(text)
+            .block(pane_block("Diff", focused))
+            .scroll((self.scroll, 0));
+        f.render_widget(paragraph, area);
+    }
+}
+
+fn render(details: &Details) -> Vec<Spans<'static>> {
+    let mut text = vec![];
+    for hunk in details.hunks() {
+        text.push(Spans::from(Span::styled(
+            trim_newline(hunk.header()),
+            Style::default().fg(Color::Cyan),
+        )));
+        text.extend(hunk.lines().iter().map(render_line));
+    }
+    text
+}
+
+fn render_line(line: &Line) -> Spans<'static> {
+    use git2::DiffLineType;
+
+    let (prefix, style) = match line.origin() {
+        DiffLineType::Addition => ('+', Style::default().fg(Color::Green)),
+        DiffLineType::Deletion => ('-', Style::default().fg(Color::Red)),
+        DiffLineType::ContextEOFNL | DiffLineType::AddEOFNL | DiffLineType::DeleteEOFNL => {
+            return Spans::from(Span::styled(
+                "\\ No newline at end of file",
+                Style::default().fg(Color::DarkGray),
+            ));
+        }
+        _ => (' ', Style::default()),
+    };
+    Spans::from(Span::styled(
+        format!("{}{}", prefix, trim_newline(line.content())),
+        style,
+    ))
+}
+
+fn trim_newline(content: &[u8]) -> String {
+    let content = content.strip_suffix(b"\n").unwrap_or(content);
+    String::from_utf8_lossy(content).into_owned()
+}
@@ -0,0 +1,168 @@
+use std::{convert::TryFrom, time::SystemTime};
+
+use idgit::{HistoryBranch, HistoryEntry};
+use tui::{
+    backend::Backend,
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
+    widgets::{List, ListItem, ListState},
+    Frame,
+};
+
+use super::pane_block;
+
+/// A timeline of everything done in idgit, which can be jumped around in.
+///
+/// Branches are shown indented under the change they fork from, so undone
+/// changes that were replaced by something else can still be got back to.
+pub(super) struct Pane {
+    rows: Vec<Row>,
+    selected: usize,
+    state: ListState,
+}
+
+/// A point in the history that can be jumped to
+struct Row {
+    branch: usize,
+    position: usize,
+    /// `None` for before anything was done
+    entry: Option<HistoryEntry>,
+    indent: usize,
+    /// If this is the first change in a branch other than the root
+    forks: bool,
+}
+
+impl Pane {
+    pub(super) fn new() -> Self {
+        Self {
+            rows: vec![],
+            selected: 0,
+            state: ListState::default(),
+        }
+    }
+
+    /// Replace the history shown and select the current point in it.
+    pub(super) fn set(&mut self, branches: &[HistoryBranch]) {
+        let mut rows = vec![Row {
+            branch: 0,
+            position: 0,
+            entry: None,
+            indent: 0,
+            forks: false,
+        }];
+        push_forks(&mut rows, branches, 0, 0, 1);
+        push_branch(&mut rows, branches, 0, 0);
+
+        self.selected = rows
+            .iter()
+            .position(|row| row.entry.as_ref().map_or(false, HistoryEntry::is_current))
+            .unwrap_or(0);
+        self.rows = rows;
+    }
+
+    /// The branch and position [`idgit::Repo::goto`] should go to for the
+    /// selected row
+    pub(super) fn selected(&self) -> (usize, usize) {
+        let row = &self.rows[self.selected];
+        (row.branch, row.position)
+    }
+
+    pub(super) fn move_by(&mut self, delta: isize) {
+        let max = self.rows.len().saturating_sub(1);
+        let moved = isize::try_from(self.selected).unwrap_or(isize::MAX) + delta;
+        self.selected = usize::try_from(moved).unwrap_or(0).min(max);
+    }
+
+    pub(super) fn draw<B: Backend>(&mut self, f: &mut Frame<B>, area: Rect, focused: bool) {
+        let nothing_applied = !self
+            .rows
+            .iter()
+            .any(|row| row.entry.as_ref().map_or(false, HistoryEntry::is_applied));
+        let now = SystemTime::now();
+
+        let items = self
+            .rows
+            .iter()
+            .map(|row| ListItem::new(draw_row(row, nothing_applied, now)))
+            .collect::<Vec<_>>();
+        self.state.select(Some(self.selected));
+
+        let list = List::new(items)
+            .block(pane_block("History", focused))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        f.render_stateful_widget(list, area, &mut self.state);
+    }
+}
+
+fn push_branch(rows: &mut Vec<Row>, branches: &[HistoryBranch], id: usize, indent: usize) {
+    for (i, entry) in branches[id].entries().iter().enumerate() {
+        rows.push(Row {
+            branch: id,
+            position: entry.position(),
+            entry: Some(entry.clone()),
+            indent,
+            forks: i == 0 && id != 0,
+        });
+        push_forks(rows, branches, id, entry.position(), indent + 1);
+    }
+}
+
+/// Add the branches that start from `position` in branch `id`
+fn push_forks(
+    rows: &mut Vec<Row>,
+    branches: &[HistoryBranch],
+    id: usize,
+    position: usize,
+    indent: usize,
+) {
+    for branch in branches {
+        if branch.parent() == Some((id, position)) {
+            push_branch(rows, branches, branch.id(), indent);
+        }
+    }
+}
+
+/// Undone changes are dimmed, as they could be redone.
+fn draw_row(row: &Row, nothing_applied: bool, now: SystemTime) -> Spans<'static> {
+    let (current, applied, description) = match &row.entry {
+        Some(entry) => (
+            entry.is_current(),
+            entry.is_applied(),
+            entry.description().to_owned(),
+        ),
+        None => (nothing_applied, true, "Start".to_owned()),
+    };
+
+    let style = if applied {
+        Style::default()
+    } else {
+        Style::default().fg(Color::DarkGray)
+    };
+    let marker = if current { "● " } else { "○ " };
+    let branch = if row.forks { "└ " } else { "  " };
+
+    let mut spans = vec![
+        Span::raw("  ".repeat(row.indent.saturating_sub(1))),
+        Span::styled(if row.indent > 0 { branch } else { "" }, style),
+        Span::styled(marker, style),
+        Span::styled(description, style),
+    ];
+    if let Some(entry) = &row.entry {
+        spans.push(Span::styled(
+            format!("  {}", ago(now, entry.timestamp())),
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+    Spans::from(spans)
+}
+
+fn ago(now: SystemTime, then: SystemTime) -> String {
+    let secs = now.duration_since(then).map_or(0, |since| since.as_secs());
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
@@ -0,0 +1,156 @@
+use std::convert::TryFrom;
+
+use idgit::{Graph, GraphRow, LogCommit};
+use tui::{
+    backend::Backend,
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
+    widgets::{List, ListItem, ListState},
+    Frame,
+};
+
+use super::pane_block;
+
+/// Lanes are told apart by colour, which repeats once there are more lanes
+/// than this.
+const LANE_COLORS: [Color; 6] = [
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::Green,
+    Color::Yellow,
+    Color::Red,
+];
+
+/// Box drawing characters, indexed by the sides they join. Up, down, left
+/// and right are each a bit, starting from the lowest.
+const LINES: [char; 16] = [
+    ' ', '╵', '╷', '│', '╴', '╯', '╮', '┤', '╶', '╰', '╭', '├', '─', '┴', '┬', '┼',
+];
+
+/// The commits in the repository, drawn as a graph of how they're related.
+pub(super) struct Pane {
+    commits: Vec<LogCommit>,
+    graph: Graph,
+    selected: usize,
+    state: ListState,
+}
+
+impl Pane {
+    pub(super) fn new() -> Self {
+        Self {
+            commits: vec![],
+            graph: Graph::new(),
+            selected: 0,
+            state: ListState::default(),
+        }
+    }
+
+    /// Replace the commits shown, which should be in topological order,
+    /// keeping the same commit selected if it's still there.
+    pub(super) fn set(&mut self, commits: Vec<LogCommit>) {
+        let selected = self.commits.get(self.selected).map(LogCommit::id);
+        self.selected = selected
+            .and_then(|id| commits.iter().position(|commit| commit.id() == id))
+            .unwrap_or(0);
+        self.graph = commits.iter().collect();
+        self.commits = commits;
+    }
+
+    pub(super) fn move_by(&mut self, delta: isize) {
+        let max = self.commits.len().saturating_sub(1);
+        let moved = isize::try_from(self.selected).unwrap_or(isize::MAX) + delta;
+        self.selected = usize::try_from(moved).unwrap_or(0).min(max);
+    }
+
+    pub(super) fn draw<B: Backend>(&mut self, f: &mut Frame<B>, area: Rect, focused: bool) {
+        let items = self
+            .commits
+            .iter()
+            .zip(self.graph.rows())
+            .map(|(commit, row)| ListItem::new(draw_row(commit, row)))
+            .collect::<Vec<_>>();
+        if !items.is_empty() {
+            self.state.select(Some(self.selected));
+        }
+
+        let list = List::new(items)
+            .block(pane_block("Log", focused))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        f.render_stateful_widget(list, area, &mut self.state);
+    }
+}
+
+fn draw_row(commit: &LogCommit, row: &GraphRow) -> Spans<'static> {
+    let mut spans = draw_graph(row);
+
+    let id = commit.id().to_string();
+    spans.push(Span::styled(
+        format!("{} ", &id[..7]),
+        Style::default().fg(Color::Yellow),
+    ));
+    if !commit.refs().is_empty() {
+        spans.push(Span::styled(
+            format!("({}) ", commit.refs().join(", ")),
+            Style::default().fg(Color::Green),
+        ));
+    }
+    spans.push(Span::raw(commit.summary().to_owned()));
+    Spans::from(spans)
+}
+
+/// One character per lane, with lines from the commit to the lanes it joins
+/// drawn across the gaps between them.
+fn draw_graph(row: &GraphRow) -> Vec<Span<'static>> {
+    let column = row.column();
+    let (left, right) = row
+        .merged()
+        .iter()
+        .chain(row.parents())
+        .fold((column, column), |(left, right), lane| {
+            (left.min(*lane), right.max(*lane))
+        });
+    let across = Style::default().fg(lane_color(column));
+
+    let mut spans = Vec::with_capacity(row.width() * 2);
+    for lane in 0..row.width() {
+        let symbol = if lane == column {
+            '●'
+        } else {
+            let through = row.through().contains(&lane);
+            let sides = [
+                through || row.merged().contains(&lane),
+                through || row.parents().contains(&lane),
+                lane > left && lane <= right,
+                lane >= left && lane < right,
+            ];
+            LINES[sides
+                .iter()
+                .rev()
+                .fold(0, |index, side| index * 2 + usize::from(*side))]
+        };
+        let style = if lane == column || through_or_joined(row, lane) {
+            Style::default().fg(lane_color(lane))
+        } else {
+            across
+        };
+        spans.push(Span::styled(symbol.to_string(), style));
+
+        let gap = if lane >= left && lane < right {
+            "─"
+        } else {
+            " "
+        };
+        spans.push(Span::styled(gap, across));
+    }
+    spans
+}
+
+fn through_or_joined(row: &GraphRow, lane: usize) -> bool {
+    row.through().contains(&lane) || row.merged().contains(&lane) || row.parents().contains(&lane)
+}
+
+fn lane_color(lane: usize) -> Color {
+    LANE_COLORS[lane % LANE_COLORS.len()]
+}
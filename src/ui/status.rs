@@ -0,0 +1,139 @@
+use std::{borrow::Cow, convert::TryFrom};
+
+use idgit::{Meta, RepoFile};
+use tui::{
+    backend::Backend,
+    layout::Rect,
+    style::{Modifier, Style},
+    text::{Span, Spans},
+    widgets::{List, ListItem, ListState},
+    Frame,
+};
+
+use super::pane_block;
+
+/// Lists the uncommitted files, grouped by what kind of change they have.
+pub(super) struct Pane {
+    files: Vec<Meta>,
+    selected: Option<usize>,
+    state: ListState,
+}
+
+impl Pane {
+    pub(super) fn new() -> Self {
+        Self {
+            files: vec![],
+            selected: None,
+            state: ListState::default(),
+        }
+    }
+
+    /// Replace the files shown, keeping the same file selected if it's still
+    /// there.
+    pub(super) fn set(&mut self, mut files: Vec<Meta>) {
+        files.sort_by(|a, b| {
+            kind(a)
+                .0
+                .cmp(&kind(b).0)
+                .then_with(|| a.file().rel_path().cmp(&b.file().rel_path()))
+        });
+
+        let previous = self
+            .selected()
+            .map(|meta| meta.file().rel_path().map(ToOwned::to_owned));
+        let kept = previous.and_then(|previous| {
+            files
+                .iter()
+                .position(|meta| meta.file().rel_path() == previous.as_deref())
+        });
+
+        self.selected = if files.is_empty() {
+            None
+        } else {
+            Some(kept.unwrap_or_else(|| self.selected.unwrap_or(0).min(files.len() - 1)))
+        };
+        self.files = files;
+    }
+
+    pub(super) fn selected(&self) -> Option<&Meta> {
+        self.selected.map(|i| &self.files[i])
+    }
+
+    /// The heading of the group the selected file is in, and every file in
+    /// that group
+    pub(super) fn selected_group(&self) -> Option<(&'static str, Vec<Meta>)> {
+        let (order, heading) = kind(self.selected()?);
+        let files = self
+            .files
+            .iter()
+            .filter(|meta| kind(meta).0 == order)
+            .cloned()
+            .collect();
+        Some((heading, files))
+    }
+
+    pub(super) fn move_by(&mut self, delta: isize) {
+        if let Some(selected) = self.selected {
+            let max = self.files.len() - 1;
+            let moved = isize::try_from(selected).unwrap_or(isize::MAX) + delta;
+            self.selected = Some(usize::try_from(moved).unwrap_or(0).min(max));
+        }
+    }
+
+    pub(super) fn draw<B: Backend>(&mut self, f: &mut Frame<B>, area: Rect, focused: bool) {
+        let mut items = vec![];
+        let mut selected_row = None;
+        let mut group = None;
+
+        for (i, meta) in self.files.iter().enumerate() {
+            let (order, heading) = kind(meta);
+            if group != Some(order) {
+                group = Some(order);
+                items.push(ListItem::new(Spans::from(Span::styled(
+                    heading,
+                    Style::default().add_modifier(Modifier::BOLD),
+                ))));
+            }
+            if self.selected == Some(i) {
+                selected_row = Some(items.len());
+            }
+            items.push(ListItem::new(format!("  {}", display_meta(meta))));
+        }
+        self.state.select(selected_row);
+
+        let list = List::new(items)
+            .block(pane_block("Status", focused))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        f.render_stateful_widget(list, area, &mut self.state);
+    }
+}
+
+/// Where a kind of change is listed, and the heading it's listed under
+fn kind(meta: &Meta) -> (u8, &'static str) {
+    match meta {
+        Meta::Conflicted { .. } => (0, "Conflicted"),
+        Meta::Modified { .. } => (1, "Modified"),
+        Meta::Typechange { .. } => (2, "Type changed"),
+        Meta::Renamed { .. } => (3, "Renamed"),
+        Meta::Copied { .. } => (4, "Copied"),
+        Meta::Added(_) => (5, "Added"),
+        Meta::Deleted(_) => (6, "Deleted"),
+        Meta::Untracked(_) => (7, "Untracked"),
+        Meta::Unreadable(_) => (8, "Unreadable"),
+        Meta::Ignored(_) => (9, "Ignored"),
+    }
+}
+
+pub(super) fn display_meta(meta: &Meta) -> String {
+    match meta {
+        Meta::Renamed { old, new } | Meta::Copied { old, new } => {
+            format!("{} -> {}", display_path(old), display_path(new))
+        }
+        _ => display_path(meta.file()).into_owned(),
+    }
+}
+
+fn display_path(file: &RepoFile) -> Cow<'_, str> {
+    file.rel_path()
+        .map_or(Cow::Borrowed("<unknown>"), |path| path.to_string_lossy())
+}
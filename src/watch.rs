@@ -0,0 +1,125 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use notify::{DebouncedEvent, RecursiveMode, Watcher as _};
+
+use crate::{repo::Internal, Result, Status};
+#[allow(unused)]
+use tracing::{debug, error, info, instrument, span, warn};
+
+/// How long to wait for things to settle after a change. Editors often write a
+/// file in several steps, and git writes several files for one command.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// A stream of the status of a repository, sent whenever something changes.
+///
+/// Stops watching when dropped.
+pub struct Subscription {
+    statuses: mpsc::Receiver<Result<Vec<Status>>>,
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl Subscription {
+    /// Watch the workdir of the repository at `path`, along with the parts of
+    /// `.git` that affect its status.
+    pub(crate) fn new(path: &Path) -> Result<Self> {
+        let internal = Internal::open(path)?;
+        let (events_tx, events) = mpsc::channel();
+        let mut watcher = notify::watcher(events_tx, DEBOUNCE)?;
+        watcher.watch(internal.path(), RecursiveMode::Recursive)?;
+
+        let (statuses_tx, statuses) = mpsc::channel();
+        thread::spawn(move || {
+            let relevant = Relevant::new(&internal);
+            while let Ok(event) = events.recv() {
+                if !relevant.event(&event) {
+                    continue;
+                }
+                // Whatever else came in while we were waiting is covered by
+                // the status we're about to send.
+                while events.try_recv().is_ok() {}
+
+                if statuses_tx.send(internal.status()).is_err() {
+                    debug!("Subscription dropped, stopping");
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            statuses,
+            _watcher: watcher,
+        })
+    }
+
+    /// Wait for the next change.
+    pub fn recv(&self) -> Option<Result<Vec<Status>>> {
+        self.statuses.recv().ok()
+    }
+
+    /// Wait for the next change, giving up after `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Result<Vec<Status>>> {
+        self.statuses.recv_timeout(timeout).ok()
+    }
+
+    /// The latest change if there has been one since last checked, without
+    /// waiting.
+    pub fn try_recv(&self) -> Option<Result<Vec<Status>>> {
+        self.statuses.try_iter().last()
+    }
+
+    /// Every change, waiting for each.
+    pub fn iter(&self) -> impl Iterator<Item = Result<Vec<Status>>> + '_ {
+        self.statuses.iter()
+    }
+}
+
+/// Decides which filesystem events could change the status.
+struct Relevant<'a> {
+    internal: &'a Internal,
+    git_dir: PathBuf,
+}
+
+impl<'a> Relevant<'a> {
+    fn new(internal: &'a Internal) -> Self {
+        Self {
+            internal,
+            git_dir: internal.git_dir().to_path_buf(),
+        }
+    }
+
+    fn event(&self, event: &DebouncedEvent) -> bool {
+        match event {
+            DebouncedEvent::Create(path)
+            | DebouncedEvent::Write(path)
+            | DebouncedEvent::Chmod(path)
+            | DebouncedEvent::Remove(path) => self.path(path),
+            DebouncedEvent::Rename(from, to) => self.path(from) || self.path(to),
+            // Events were dropped, so anything could have changed
+            DebouncedEvent::Rescan => true,
+            DebouncedEvent::Error(err, path) => {
+                warn!("Error watching {:?}: {}", path, err);
+                false
+            }
+            DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => false,
+        }
+    }
+
+    fn path(&self, path: &Path) -> bool {
+        if let Ok(in_git) = path.strip_prefix(&self.git_dir) {
+            return in_git == Path::new("HEAD")
+                || in_git == Path::new("index")
+                || in_git.starts_with("refs");
+        }
+
+        match path.strip_prefix(self.internal.path()) {
+            // Build output and the like changing doesn't change the status
+            Ok(rel) => !self.internal.is_ignored(rel).unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+}
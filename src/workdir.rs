@@ -0,0 +1,152 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    io::ErrorKind,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{symlink, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{serde_git, Result};
+#[allow(unused)]
+use tracing::{debug, error, info, instrument, span, warn};
+
+/// The contents of a workdir file saved as a blob in the object database, so
+/// it can be written back after being overwritten.
+///
+/// Nothing refers to these blobs but our history, so `git gc` may eventually
+/// prune them once they are older than `gc.pruneExpire`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Blob {
+    #[serde(with = "serde_git::oid")]
+    id: git2::Oid,
+    mode: u32,
+}
+
+impl Blob {
+    pub(crate) fn new(id: git2::Oid, mode: u32) -> Self {
+        Self { id, mode }
+    }
+
+    pub(crate) fn id(&self) -> git2::Oid {
+        self.id
+    }
+
+    pub(crate) fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// Save the current contents of the file at `abs`, or `None` if there is
+    /// no file.
+    pub(crate) fn save(git: &git2::Repository, abs: &Path) -> Result<Option<Self>> {
+        let meta = match fs::symlink_metadata(abs) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let blob = if meta.file_type().is_symlink() {
+            let target = fs::read_link(abs)?;
+            let id = git.blob(target.as_os_str().as_bytes())?;
+            Self::new(id, git2::FileMode::Link.into())
+        } else {
+            let id = git.blob(&fs::read(abs)?)?;
+            let mode = if meta.permissions().mode() & 0o111 == 0 {
+                git2::FileMode::Blob
+            } else {
+                git2::FileMode::BlobExecutable
+            };
+            Self::new(id, mode.into())
+        };
+
+        Ok(Some(blob))
+    }
+
+    /// Make the file at `abs` exactly `blob`, or remove it if `None`.
+    pub(crate) fn restore(git: &git2::Repository, abs: &Path, blob: Option<&Self>) -> Result<()> {
+        let existing = match fs::symlink_metadata(abs) {
+            Ok(meta) => Some(meta),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        let blob = if let Some(blob) = blob {
+            blob
+        } else {
+            if existing.is_some() {
+                fs::remove_file(abs)?;
+            }
+            return Ok(());
+        };
+
+        let content = git.find_blob(blob.id)?;
+        let content = content.content();
+
+        if blob.mode == u32::from(git2::FileMode::Link) {
+            if existing.is_some() {
+                fs::remove_file(abs)?;
+            }
+            symlink(OsStr::from_bytes(content), abs)?;
+            return Ok(());
+        }
+
+        if existing.map_or(false, |meta| meta.file_type().is_symlink()) {
+            fs::remove_file(abs)?;
+        }
+        if let Some(parent) = abs.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(abs, content)?;
+
+        let mut perms = fs::metadata(abs)?.permissions();
+        if blob.mode == u32::from(git2::FileMode::BlobExecutable) {
+            perms.set_mode(perms.mode() | 0o111);
+        } else {
+            perms.set_mode(perms.mode() & !0o111);
+        }
+        fs::set_permissions(abs, perms)?;
+
+        Ok(())
+    }
+}
+
+/// The contents of a workdir file before and after some change, so the change
+/// can be undone and redone exactly.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Update {
+    path: PathBuf,
+    before: Option<Blob>,
+    after: Option<Blob>,
+}
+
+impl Update {
+    /// Save the current contents of `path` and replace them with `after`.
+    pub(crate) fn record(
+        git: &git2::Repository,
+        workdir: &Path,
+        path: &Path,
+        after: Option<Blob>,
+    ) -> Result<Self> {
+        let abs = workdir.join(path);
+        let before = Blob::save(git, &abs)?;
+        Blob::restore(git, &abs, after.as_ref())?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            before,
+            after,
+        })
+    }
+
+    pub(crate) fn apply(&self, git: &git2::Repository, workdir: &Path) -> Result<()> {
+        Blob::restore(git, &workdir.join(&self.path), self.after.as_ref())
+    }
+
+    pub(crate) fn revert(&self, git: &git2::Repository, workdir: &Path) -> Result<()> {
+        Blob::restore(git, &workdir.join(&self.path), self.before.as_ref())
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+};
+
+use crate::{diff, index, repo::Internal, Error, LogCommit, LogOptions, Result, StatusOptions};
+#[allow(unused)]
+use tracing::{debug, error, info, instrument, span, warn};
+
+/// How many files' details to remember before starting again. Enough for
+/// someone scrolling back and forth through a long list of changes.
+const MAX_CACHED: usize = 1024;
+
+/// Works out the uncommitted files, their diffs and the log on a background
+/// thread, so a large repository doesn't hold up whoever asked.
+///
+/// Asking again supersedes the last request of the same kind, which is
+/// cancelled if it hasn't been answered yet. Details are cached by the blobs
+/// in HEAD and the index and the stat information of the workdir file, so
+/// asking for an unchanged file again is cheap.
+///
+/// Stops when dropped, cancelling anything in progress.
+pub struct Worker {
+    requests: mpsc::Sender<Request>,
+    latest: Arc<Latest>,
+    cache_hits: Arc<AtomicU64>,
+}
+
+/// An answer from a [`Worker`]. Requests that were cancelled or superseded
+/// aren't answered.
+#[derive(Debug)]
+pub enum Response {
+    /// Every uncommitted file, answering [`Worker::files`]
+    Files(Result<Vec<diff::Meta>>),
+    /// Details of the changes to a file, answering [`Worker::details`]
+    Details(diff::Meta, Result<diff::Details>),
+    /// The start of the log, answering [`Worker::log`]
+    Log(Result<Vec<LogCommit>>),
+}
+
+enum Request {
+    Files(u64, StatusOptions),
+    Details(u64, diff::Meta),
+    Log(u64, LogOptions, usize),
+}
+
+/// The newest request of each kind. Anything older is cancelled.
+#[derive(Default)]
+struct Latest {
+    files: AtomicU64,
+    details: AtomicU64,
+    log: AtomicU64,
+}
+
+impl Latest {
+    fn is(counter: &AtomicU64, generation: u64) -> bool {
+        counter.load(Ordering::SeqCst) == generation
+    }
+
+    fn next(counter: &AtomicU64) -> u64 {
+        counter.fetch_add(1, Ordering::SeqCst) + 1
+    }
+}
+
+impl Worker {
+    /// Work on the repository at `path`, passing each answer to `respond` on
+    /// the worker's thread.
+    pub(crate) fn new<F>(path: &Path, mut respond: F) -> Result<Self>
+    where
+        F: FnMut(Response) + Send + 'static,
+    {
+        let internal = Internal::open(path)?;
+        let latest = Arc::new(Latest::default());
+        let (requests_tx, requests) = mpsc::channel();
+
+        let cache_hits = Arc::new(AtomicU64::new(0));
+
+        let worker_latest = Arc::clone(&latest);
+        let worker_cache_hits = Arc::clone(&cache_hits);
+        thread::spawn(move || {
+            let latest = worker_latest;
+            let mut cache = Cache {
+                entries: HashMap::new(),
+                hits: worker_cache_hits,
+            };
+            while let Ok(request) = requests.recv() {
+                let response = match request {
+                    Request::Files(generation, options) => {
+                        let cancelled = || !Latest::is(&latest.files, generation);
+                        match internal.uncommitted_files_unless(&options, &cancelled) {
+                            Err(Error::Cancelled) => continue,
+                            files => Response::Files(files),
+                        }
+                    }
+                    Request::Details(generation, meta) => {
+                        let cancelled = || !Latest::is(&latest.details, generation);
+                        match cache.details(&internal, &meta, &cancelled) {
+                            Err(Error::Cancelled) => continue,
+                            details => Response::Details(meta, details),
+                        }
+                    }
+                    Request::Log(generation, options, limit) => {
+                        let cancelled = || !Latest::is(&latest.log, generation);
+                        match read_log(&internal, &options, limit, &cancelled) {
+                            Err(Error::Cancelled) => continue,
+                            commits => Response::Log(commits),
+                        }
+                    }
+                };
+                respond(response);
+            }
+            debug!("Worker dropped, stopping");
+        });
+
+        Ok(Self {
+            requests: requests_tx,
+            latest,
+            cache_hits,
+        })
+    }
+
+    /// Work out every uncommitted file, as in [`crate::Repo::uncommitted_files`].
+    pub fn files(&self, options: &StatusOptions) {
+        let generation = Latest::next(&self.latest.files);
+        self.send(Request::Files(generation, options.clone()));
+    }
+
+    /// Work out the details of the changes to a file, as in
+    /// [`crate::Repo::diff_details`].
+    pub fn details(&self, meta: &diff::Meta) {
+        let generation = Latest::next(&self.latest.details);
+        self.send(Request::Details(generation, meta.clone()));
+    }
+
+    /// How many requests for details have been answered from the cache so far
+    pub fn cache_hits(&self) -> u64 {
+        self.cache_hits.load(Ordering::SeqCst)
+    }
+
+    /// Read the first `limit` commits of the log, as in [`crate::Repo::log`].
+    pub fn log(&self, options: &LogOptions, limit: usize) {
+        let generation = Latest::next(&self.latest.log);
+        self.send(Request::Log(generation, options.clone(), limit));
+    }
+
+    /// Cancel everything asked for so far.
+    pub fn cancel(&self) {
+        Latest::next(&self.latest.files);
+        Latest::next(&self.latest.details);
+        Latest::next(&self.latest.log);
+    }
+
+    fn send(&self, request: Request) {
+        if self.requests.send(request).is_err() {
+            error!("Worker thread stopped unexpectedly");
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Up to `limit` commits from the start of the log, giving up with
+/// [`Error::Cancelled`] between any two once `cancelled` returns true
+fn read_log(
+    internal: &Internal,
+    options: &LogOptions,
+    limit: usize,
+    cancelled: &dyn Fn() -> bool,
+) -> Result<Vec<LogCommit>> {
+    let mut commits = vec![];
+    for commit in internal.log(options)?.take(limit) {
+        if cancelled() {
+            return Err(Error::Cancelled);
+        }
+        commits.push(commit?);
+    }
+    Ok(commits)
+}
+
+/// Details already worked out, along with what they were worked out from
+struct Cache {
+    entries: HashMap<PathBuf, (Key, diff::Details)>,
+    /// Shared with the [`Worker`] to count how often the cache is used
+    hits: Arc<AtomicU64>,
+}
+
+impl Cache {
+    fn details(
+        &mut self,
+        internal: &Internal,
+        meta: &diff::Meta,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<diff::Details> {
+        let path = meta.file().rel_path_required()?;
+        let key = Key::take(internal, path)?;
+
+        if let (Some(key), Some((cached_key, details))) = (&key, self.entries.get(path)) {
+            if key == cached_key {
+                self.hits.fetch_add(1, Ordering::SeqCst);
+                return Ok(details.clone());
+            }
+        }
+
+        let details = internal.diff_details_unless(meta, cancelled)?;
+        if let Some(key) = key {
+            if self.entries.len() >= MAX_CACHED {
+                self.entries.clear();
+            }
+            self.entries
+                .insert(path.to_path_buf(), (key, details.clone()));
+        }
+        Ok(details)
+    }
+}
+
+/// Everything the details of the changes to a path depend on
+#[derive(Debug, PartialEq, Eq)]
+struct Key {
+    head: Option<(git2::Oid, u32)>,
+    index: Option<(git2::Oid, u32)>,
+    workdir: Option<Stat>,
+}
+
+impl Key {
+    /// `None` if the details of `path` can't be cached, such as for an
+    /// untracked directory whose stat information doesn't change when the
+    /// files in it do.
+    fn take(internal: &Internal, path: &Path) -> Result<Option<Self>> {
+        let workdir = match fs::symlink_metadata(internal.path().join(path)) {
+            Ok(meta) if meta.is_dir() => return Ok(None),
+            Ok(meta) => Some(Stat::from(&meta)),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        let index = index::Entry::get(&internal.index()?, path).map(|e| (e.id(), e.mode()));
+
+        Ok(Some(Self {
+            head: internal.head_blob(path)?,
+            index,
+            workdir,
+        }))
+    }
+}
+
+/// The stat information git uses to tell if a file changed without reading
+/// it
+#[derive(Debug, PartialEq, Eq)]
+struct Stat {
+    ctime: (i64, i64),
+    mtime: (i64, i64),
+    ino: u64,
+    mode: u32,
+    size: u64,
+}
+
+impl From<&fs::Metadata> for Stat {
+    fn from(meta: &fs::Metadata) -> Self {
+        Self {
+            ctime: (meta.ctime(), meta.ctime_nsec()),
+            mtime: (meta.mtime(), meta.mtime_nsec()),
+            ino: meta.ino(),
+            mode: meta.mode(),
+            size: meta.size(),
+        }
+    }
+}
@@ -1,11 +1,18 @@
 #![feature(with_options, assert_matches)]
 
-use idgit::{Meta, Repo, Result};
+use idgit::{
+    diff::{LineId, Side},
+    CommitOptions, DiffOptions, DiscardTo, Graph, HistoryEntry, LogCommit, LogOptions, LogOrder,
+    Meta, Repo, Result, Revision, StashScope, Status, StatusOptions, Untracked, WorkerResponse,
+};
 use rand::Rng;
 use std::{
     fs::{self, File},
     io::Write,
+    panic::{self, AssertUnwindSafe},
     path::Path,
+    sync::mpsc,
+    time::{Duration, SystemTime},
 };
 
 use cmd_lib::run_cmd;
@@ -55,6 +62,7 @@ impl SampleRepoDir {
         File::with_options()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(path)
             .unwrap()
             .write_all(contents)
@@ -85,8 +93,75 @@ impl SampleRepoDir {
         })
         .unwrap();
     }
+
+    fn index_contents<N: AsRef<Path>>(&self, name: N) -> String {
+        let path = self.path_str();
+        let name = name.as_ref().to_str().unwrap();
+
+        // Not run_fun! as that trims the trailing newline we want to check
+        let out = std::process::Command::new("git")
+            .current_dir(path)
+            .args(&["show", &format!(":{}", name)])
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        String::from_utf8(out.stdout).unwrap()
+    }
+
+    /// Mode, id and stage of the index entry for `name`, or an empty string
+    /// if there isn't one
+    fn index_entry<N: AsRef<Path>>(&self, name: N) -> String {
+        let name = name.as_ref().to_str().unwrap();
+        let out = std::process::Command::new("git")
+            .current_dir(self.path())
+            .args(&["ls-files", "--stage", "--", name])
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        String::from_utf8(out.stdout).unwrap().trim().to_owned()
+    }
+
+    fn message(&self, rev: &str) -> String {
+        let out = std::process::Command::new("git")
+            .current_dir(self.path())
+            .args(&["log", "-1", "--format=%B", rev])
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        String::from_utf8(out.stdout).unwrap().trim().to_owned()
+    }
+
+    fn rev_parse(&self, rev: &str) -> Option<String> {
+        let out = std::process::Command::new("git")
+            .current_dir(self.path())
+            .args(&["rev-parse", "--verify", "--quiet", rev])
+            .output()
+            .unwrap();
+        if out.status.success() {
+            Some(String::from_utf8(out.stdout).unwrap().trim().to_owned())
+        } else {
+            None
+        }
+    }
+
+    /// Every value of the config variable `name`
+    fn config_values(&self, name: &str) -> Vec<String> {
+        let out = std::process::Command::new("git")
+            .current_dir(self.path())
+            .args(&["config", "--get-all", name])
+            .output()
+            .unwrap();
+        String::from_utf8(out.stdout)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
 }
 
+const NUMBERS: &[u8] = b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+const NUMBERS_ENDS_CHANGED: &[u8] = b"one\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\ntwelve\n";
+
 fn init_logs() {
     let _ = tracing_subscriber::fmt::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
@@ -148,7 +223,7 @@ fn uncommitted_files() -> Result<()> {
 
     dir.create_dir("example_dir");
 
-    debug!("{:#?}", repo.uncommitted_files()?);
+    debug!("{:#?}", repo.uncommitted_files(&StatusOptions::new())?);
 
     Ok(())
 }
@@ -159,7 +234,7 @@ fn uncommitted_no_commits_no_untracked() -> Result<()> {
 
     let dir = SampleRepoDir::new();
     let repo = Repo::open(dir.path())?;
-    assert_eq!(repo.uncommitted_files()?.len(), 0);
+    assert_eq!(repo.uncommitted_files(&StatusOptions::new())?.len(), 0);
 
     Ok(())
 }
@@ -175,7 +250,7 @@ fn uncommitted_no_commits_with_untracked() -> Result<()> {
 
     let repo = Repo::open(dir.path())?;
 
-    assert_matches!(repo.uncommitted_files()?.as_slice(), [
+    assert_matches!(repo.uncommitted_files(&StatusOptions::new())?.as_slice(), [
         Meta::Untracked(a), Meta::Untracked(b)] if
             a.rel_path().unwrap().to_str().unwrap() == "example_dir/" &&
             b.rel_path().unwrap().to_str().unwrap() == "name"
@@ -195,7 +270,7 @@ fn stage_file() -> Result<()> {
 
     let mut repo = Repo::open(dir.path())?;
 
-    let uncommitted = repo.uncommitted_files()?;
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
     let first_file = if let Meta::Untracked(file) = &uncommitted[0] {
         file
     } else {
@@ -205,7 +280,7 @@ fn stage_file() -> Result<()> {
     repo.stage_file(&first_file)?;
 
     assert_matches!(
-        repo.uncommitted_files()?.as_slice(),
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
         [Meta::Added(_), Meta::Untracked(_)]
     );
 
@@ -219,7 +294,7 @@ fn unstage_file() -> Result<()> {
     let mut repo = Repo::open(dir.path())?;
 
     dir.set_file("f", b"contents");
-    let uncommitted = repo.uncommitted_files()?;
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
     assert_eq!(uncommitted.len(), 1);
     let file = if let Meta::Untracked(file) = &uncommitted[0] {
         file
@@ -229,11 +304,306 @@ fn unstage_file() -> Result<()> {
 
     repo.stage_file(file)?;
 
-    assert_matches!(repo.uncommitted_files()?.as_slice(), [Meta::Added(_)]);
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Added(_)]
+    );
 
     repo.unstage_file(file)?;
 
-    assert_matches!(repo.uncommitted_files()?.as_slice(), [Meta::Untracked(_)]);
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Untracked(_)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn unstage_tracked_file_resets_to_head() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"committed");
+    dir.commit_all();
+    let head = dir.index_entry("a");
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("a", b"changed");
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
+    repo.stage_file(uncommitted[0].file())?;
+    let staged = dir.index_entry("a");
+
+    repo.unstage_file(uncommitted[0].file())?;
+    assert_eq!(dir.index_entry("a"), head);
+    let status = repo.status()?;
+    assert_matches!(status.as_slice(), [s] if s.staged().is_none());
+    assert_matches!(status[0].unstaged(), Some(Meta::Modified { .. }));
+
+    repo.undo()?;
+    assert_eq!(dir.index_entry("a"), staged);
+
+    Ok(())
+}
+
+#[test]
+fn unstage_deletion() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"committed");
+    dir.commit_all();
+    let head = dir.index_entry("a");
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.remove_file("a");
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
+    repo.stage(&uncommitted[0])?;
+    assert_eq!(dir.index_entry("a"), "");
+
+    repo.unstage(&uncommitted[0])?;
+    assert_eq!(dir.index_entry("a"), head);
+    assert_matches!(repo.status()?[0].unstaged(), Some(Meta::Deleted(_)));
+
+    Ok(())
+}
+
+#[test]
+fn uncommitted_files_options() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file(".gitignore", b"*.ignored\n");
+    dir.set_file("moved", NUMBERS);
+    dir.commit_all();
+
+    dir.set_file("example.ignored", b"ignored");
+    dir.create_dir("sub");
+    dir.set_file("sub/a", b"a");
+    dir.set_file("sub/b", b"b");
+    dir.remove_file("moved");
+    dir.set_file("renamed", NUMBERS);
+    dir.add("moved");
+    dir.add("renamed");
+
+    let repo = Repo::open(dir.path())?;
+
+    // Like `git status`: no ignored files, untracked directories as a whole,
+    // and renames detected
+    let files = repo.uncommitted_files(&StatusOptions::new())?;
+    assert_matches!(
+        files.as_slice(),
+        [Meta::Renamed { old, new }, Meta::Untracked(sub)] if
+            old.rel_path().unwrap() == Path::new("moved") &&
+            new.rel_path().unwrap() == Path::new("renamed") &&
+            sub.rel_path().unwrap() == Path::new("sub/")
+    );
+
+    let files = repo.uncommitted_files(
+        StatusOptions::new()
+            .include_ignored(true)
+            .untracked(Untracked::All)
+            .detect_renames(None),
+    )?;
+    assert_matches!(
+        files.as_slice(),
+        [
+            Meta::Ignored(_),
+            Meta::Deleted(_),
+            Meta::Added(_),
+            Meta::Untracked(_),
+            Meta::Untracked(_)
+        ]
+    );
+
+    let files = repo.uncommitted_files(
+        StatusOptions::new()
+            .untracked(Untracked::No)
+            .pathspec("sub")
+            .pathspec("renamed"),
+    )?;
+    // Only one side of the rename matches, so it isn't found
+    assert_matches!(files.as_slice(), [Meta::Added(_)]);
+
+    Ok(())
+}
+
+#[test]
+fn stage_all_and_unstage_all() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file(".gitignore", b"ignored.txt\n");
+    dir.set_file("ignored.txt", b"ignored");
+    dir.set_file("a", b"a");
+    dir.create_dir("sub");
+    dir.set_file("sub/b", b"b");
+
+    let mut with_ignored = StatusOptions::new();
+    with_ignored.include_ignored(true);
+
+    repo.stage_all()?;
+    assert_matches!(
+        repo.uncommitted_files(&with_ignored)?.as_slice(),
+        [
+            Meta::Added(_),
+            Meta::Added(_),
+            Meta::Ignored(_),
+            Meta::Added(_)
+        ]
+    );
+    assert_eq!(repo.history_entries().len(), 1);
+
+    repo.unstage_all()?;
+    assert_matches!(
+        repo.uncommitted_files(&with_ignored)?.as_slice(),
+        [
+            Meta::Untracked(_),
+            Meta::Untracked(_),
+            Meta::Ignored(_),
+            Meta::Untracked(_)
+        ]
+    );
+
+    repo.undo()?;
+    assert_eq!(dir.index_contents("sub/b"), "b");
+    repo.undo()?;
+    assert!(!repo.can_undo());
+    assert_matches!(
+        repo.uncommitted_files(&with_ignored)?.as_slice(),
+        [
+            Meta::Untracked(_),
+            Meta::Untracked(_),
+            Meta::Ignored(_),
+            Meta::Untracked(_)
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn stage_matching_directory() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("a", b"a");
+    dir.create_dir("sub");
+    dir.set_file("sub/b", b"b");
+    dir.set_file("sub/c", b"c");
+
+    repo.stage_matching(&["sub"])?;
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Untracked(_), Meta::Added(_), Meta::Added(_)]
+    );
+    assert_eq!(repo.history_entries()[0].description(), "Staged sub");
+
+    repo.undo()?;
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Untracked(_), Meta::Untracked(_)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn stage_deleted_file() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"a");
+    dir.commit_all();
+    let mut repo = Repo::open(dir.path())?;
+    let before = dir.index_entry("a");
+
+    dir.remove_file("a");
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
+    assert_matches!(uncommitted.as_slice(), [Meta::Deleted(_)]);
+
+    repo.stage(&uncommitted[0])?;
+    assert_eq!(dir.index_entry("a"), "");
+    assert_matches!(repo.status()?[0].staged(), Some(Meta::Deleted(_)));
+
+    repo.undo()?;
+    assert_eq!(dir.index_entry("a"), before);
+
+    repo.redo()?;
+    assert_eq!(dir.index_entry("a"), "");
+
+    Ok(())
+}
+
+#[test]
+fn stage_executable_bit() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"a");
+    dir.commit_all();
+    let mut repo = Repo::open(dir.path())?;
+    let before = dir.index_entry("a");
+    assert!(before.starts_with("100644"));
+
+    let path = dir.path().join("a");
+    let mut perms = fs::metadata(&path).unwrap().permissions();
+    perms.set_mode(0o755);
+    fs::set_permissions(&path, perms).unwrap();
+
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
+    assert_matches!(uncommitted.as_slice(), [Meta::Modified { .. }]);
+    repo.stage(&uncommitted[0])?;
+    assert!(dir.index_entry("a").starts_with("100755"));
+
+    repo.undo()?;
+    assert_eq!(dir.index_entry("a"), before);
+
+    Ok(())
+}
+
+#[test]
+fn stage_typechange() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("target", b"target");
+    dir.set_file("link", b"not a link yet");
+    dir.commit_all();
+    let mut repo = Repo::open(dir.path())?;
+    let before = dir.index_entry("link");
+
+    dir.remove_file("link");
+    std::os::unix::fs::symlink("target", dir.path().join("link")).unwrap();
+
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
+    assert_matches!(uncommitted.as_slice(), [Meta::Typechange { .. }]);
+    repo.stage(&uncommitted[0])?;
+    assert!(dir.index_entry("link").starts_with("120000"));
+    assert_eq!(dir.index_contents("link"), "target");
+
+    repo.undo()?;
+    assert_eq!(dir.index_entry("link"), before);
+
+    Ok(())
+}
+
+#[test]
+fn undo_stage_restores_previous_entry() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"committed");
+    dir.commit_all();
+    dir.set_file("a", b"staged");
+    dir.add("a");
+    dir.set_file("a", b"in workdir");
+    let mut repo = Repo::open(dir.path())?;
+
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
+    repo.stage_file(uncommitted[0].file())?;
+    assert_eq!(dir.index_contents("a"), "in workdir");
+
+    // Undoing puts back what was staged before, rather than unstaging
+    repo.undo()?;
+    assert_eq!(dir.index_contents("a"), "staged");
 
     Ok(())
 }
@@ -245,7 +615,7 @@ fn undo_redo_stage_file() -> Result<()> {
     let mut repo = Repo::open(dir.path())?;
 
     dir.set_file("f", b"contents");
-    let uncommitted = repo.uncommitted_files()?;
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
     assert_eq!(uncommitted.len(), 1);
     let file = if let Meta::Untracked(file) = &uncommitted[0] {
         file
@@ -254,13 +624,48 @@ fn undo_redo_stage_file() -> Result<()> {
     };
 
     repo.stage_file(file)?;
-    assert_matches!(repo.uncommitted_files()?.as_slice(), [Meta::Added(_)]);
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Added(_)]
+    );
 
     repo.undo()?;
-    assert_matches!(repo.uncommitted_files()?.as_slice(), [Meta::Untracked(_)]);
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Untracked(_)]
+    );
 
     repo.redo()?;
-    assert_matches!(repo.uncommitted_files()?.as_slice(), [Meta::Added(_)]);
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Added(_)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn repo_outlives_files() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("f", b"contents");
+    {
+        let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
+        repo.stage_file(uncommitted[0].file())?;
+    }
+
+    // Nothing in the history borrows the files, so the repo can move elsewhere
+    let handle = std::thread::spawn(move || -> Result<Repo> {
+        repo.undo()?;
+        Ok(repo)
+    });
+    let repo = handle.join().unwrap()?;
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Untracked(_)]
+    );
 
     Ok(())
 }
@@ -272,7 +677,7 @@ fn undo_redo_unstage_file() -> Result<()> {
     let mut repo = Repo::open(dir.path())?;
 
     dir.set_file("f", b"contents");
-    let uncommitted = repo.uncommitted_files()?;
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
     assert_eq!(uncommitted.len(), 1);
     let file = if let Meta::Untracked(file) = &uncommitted[0] {
         file
@@ -282,7 +687,7 @@ fn undo_redo_unstage_file() -> Result<()> {
 
     repo.stage_file(file)?;
 
-    let uncommitted = repo.uncommitted_files()?;
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
     assert_matches!(uncommitted.as_slice(), [Meta::Added(_)]);
     let file = if let Meta::Added(file) = &uncommitted[0] {
         file
@@ -291,13 +696,22 @@ fn undo_redo_unstage_file() -> Result<()> {
     };
 
     repo.unstage_file(file)?;
-    assert_matches!(repo.uncommitted_files()?.as_slice(), [Meta::Untracked(_)]);
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Untracked(_)]
+    );
 
     repo.undo()?;
-    assert_matches!(repo.uncommitted_files()?.as_slice(), [Meta::Added(_)]);
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Added(_)]
+    );
 
     repo.redo()?;
-    assert_matches!(repo.uncommitted_files()?.as_slice(), [Meta::Untracked(_)]);
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Untracked(_)]
+    );
 
     Ok(())
 }
@@ -330,12 +744,25 @@ fn uncommitted_change() -> Result<()> {
     dir.commit_all();
     dir.set_file("file", new);
 
-    let uncommitted = repo.uncommitted_files()?;
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
     let diff = &uncommitted[0];
 
     let changes = repo.diff_details(diff)?;
     debug!(?changes);
 
+    assert_eq!(changes.hunks().len(), 1);
+    let hunk = &changes.hunks()[0];
+    assert!(hunk.header().starts_with(b"@@ -1,8 +1,8 @@"));
+    assert_eq!(hunk.old_start(), 1);
+    assert_eq!(hunk.new_lines(), 8);
+    assert_eq!(
+        changes
+            .lines()
+            .filter(|line| line.origin() == git2::DiffLineType::Addition)
+            .count(),
+        2
+    );
+
     Ok(())
 }
 
@@ -347,7 +774,7 @@ fn uncommitted_change_for_nonexistent_errors() -> Result<()> {
 
     dir.set_file("file", b"contents");
 
-    let uncommitted = repo.uncommitted_files()?;
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
 
     dir.remove_file("file");
 
@@ -356,3 +783,1733 @@ fn uncommitted_change_for_nonexistent_errors() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn stage_hunk() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", NUMBERS);
+    dir.commit_all();
+    dir.set_file("file", NUMBERS_ENDS_CHANGED);
+
+    let status = repo.status()?;
+    let details = repo.unstaged_details(status[0].unstaged().unwrap())?;
+    assert_eq!(details.hunks().len(), 2);
+
+    repo.stage_hunk(&details, &details.hunks()[1])?;
+    assert_eq!(
+        dir.index_contents("file"),
+        "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\ntwelve\n"
+    );
+
+    repo.undo()?;
+    assert_eq!(dir.index_contents("file").as_bytes(), NUMBERS);
+
+    repo.redo()?;
+    assert_eq!(
+        dir.index_contents("file"),
+        "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\ntwelve\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn unstage_hunk() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", NUMBERS);
+    dir.commit_all();
+    dir.set_file("file", NUMBERS_ENDS_CHANGED);
+    dir.add("file");
+
+    let status = repo.status()?;
+    let details = repo.staged_details(status[0].staged().unwrap())?;
+    assert_eq!(details.hunks().len(), 2);
+
+    repo.unstage_hunk(&details, &details.hunks()[0])?;
+    assert_eq!(
+        dir.index_contents("file"),
+        "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\ntwelve\n"
+    );
+
+    repo.undo()?;
+    assert_eq!(dir.index_contents("file").as_bytes(), NUMBERS_ENDS_CHANGED);
+
+    Ok(())
+}
+
+#[test]
+fn stage_hunk_without_trailing_newline() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", b"a\nb");
+    dir.commit_all();
+    dir.set_file("file", b"a\nc");
+
+    let status = repo.status()?;
+    let details = repo.unstaged_details(status[0].unstaged().unwrap())?;
+    repo.stage_hunk(&details, &details.hunks()[0])?;
+    assert_eq!(dir.index_contents("file"), "a\nc");
+
+    let status = repo.status()?;
+    let details = repo.staged_details(status[0].staged().unwrap())?;
+    repo.unstage_hunk(&details, &details.hunks()[0])?;
+    assert_eq!(dir.index_contents("file"), "a\nb");
+
+    // Only the final newline changes
+    dir.set_file("file", b"a\nb\n");
+
+    let status = repo.status()?;
+    let details = repo.unstaged_details(status[0].unstaged().unwrap())?;
+    repo.stage_hunk(&details, &details.hunks()[0])?;
+    assert_eq!(dir.index_contents("file"), "a\nb\n");
+
+    let status = repo.status()?;
+    let details = repo.staged_details(status[0].staged().unwrap())?;
+    repo.unstage_hunk(&details, &details.hunks()[0])?;
+    assert_eq!(dir.index_contents("file"), "a\nb");
+
+    Ok(())
+}
+
+#[test]
+fn stage_hunk_after_part_is_staged() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", NUMBERS);
+    dir.commit_all();
+    dir.set_file("file", b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n");
+    dir.add("file");
+    dir.set_file("file", b"one\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n");
+
+    // The whole diff from HEAD isn't what's being staged from
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
+    let whole = repo.diff_details(&uncommitted[0])?;
+    assert_matches!(
+        repo.stage_hunk(&whole, &whole.hunks()[0]),
+        Err(idgit::Error::WrongSide(path, Side::Unstaged)) if path == Path::new("file")
+    );
+
+    let status = repo.status()?;
+    let details = repo.unstaged_details(status[0].unstaged().unwrap())?;
+    assert_eq!(details.hunks().len(), 2);
+    repo.stage_hunk(&details, &details.hunks()[1])?;
+    assert_eq!(
+        dir.index_contents("file"),
+        "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n"
+    );
+
+    // The hunk has gone from the unstaged changes now
+    assert_matches!(
+        repo.stage_hunk(&details, &details.hunks()[1]),
+        Err(idgit::Error::HunkNotFound(_))
+    );
+    assert_matches!(
+        repo.unstage_hunk(&details, &details.hunks()[0]),
+        Err(idgit::Error::WrongSide(_, Side::Staged))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn stage_lines() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", b"a\nb\nc\n");
+    dir.commit_all();
+    dir.set_file("file", b"a\nB\nc\nd\n");
+
+    let status = repo.status()?;
+    let details = repo.unstaged_details(status[0].unstaged().unwrap())?;
+
+    // Added only
+    repo.stage_lines(&details, &[LineId::new(None, Some(2))])?;
+    assert_eq!(dir.index_contents("file"), "a\nb\nB\nc\n");
+    repo.undo()?;
+
+    // Deleted only
+    repo.stage_lines(&details, &[LineId::new(Some(2), None)])?;
+    assert_eq!(dir.index_contents("file"), "a\nc\n");
+    repo.undo()?;
+
+    // Mixed
+    repo.stage_lines(
+        &details,
+        &[LineId::new(Some(2), None), LineId::new(None, Some(4))],
+    )?;
+    assert_eq!(dir.index_contents("file"), "a\nc\nd\n");
+
+    repo.undo()?;
+    assert_eq!(dir.index_contents("file"), "a\nb\nc\n");
+
+    repo.redo()?;
+    assert_eq!(dir.index_contents("file"), "a\nc\nd\n");
+
+    Ok(())
+}
+
+#[test]
+fn stage_lines_without_trailing_newline() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", b"a\nb");
+    dir.commit_all();
+    dir.set_file("file", b"a\nb\nc");
+
+    let status = repo.status()?;
+    let details = repo.unstaged_details(status[0].unstaged().unwrap())?;
+
+    repo.stage_lines(&details, &[LineId::new(None, Some(3))])?;
+    assert_eq!(dir.index_contents("file"), "a\nb\nc");
+
+    Ok(())
+}
+
+#[test]
+fn stage_lines_after_part_is_staged() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", b"a\nb\nc\nd\n");
+    dir.commit_all();
+    dir.set_file("file", b"b\nc\nd\n");
+    dir.add("file");
+    dir.set_file("file", b"b\nd\n");
+
+    // In the diff from HEAD "c" is line 3, but in the index it's line 2
+    let uncommitted = repo.uncommitted_files(&StatusOptions::new())?;
+    let whole = repo.diff_details(&uncommitted[0])?;
+    assert_matches!(
+        repo.stage_lines(&whole, &[LineId::new(Some(3), None)]),
+        Err(idgit::Error::WrongSide(_, Side::Unstaged))
+    );
+
+    let status = repo.status()?;
+    let details = repo.unstaged_details(status[0].unstaged().unwrap())?;
+    assert_matches!(
+        repo.stage_lines(&details, &[LineId::new(Some(2), None), LineId::new(Some(3), None)]),
+        Err(idgit::Error::LinesNotFound(path, lines)) if
+            path == Path::new("file") && lines == vec![LineId::new(Some(3), None)]
+    );
+    assert_matches!(
+        repo.stage_lines(&details, &[]),
+        Err(idgit::Error::NoLinesSelected(_))
+    );
+    assert!(repo.history_entries().is_empty());
+    assert_eq!(dir.index_contents("file"), "b\nc\nd\n");
+
+    repo.stage_lines(&details, &[LineId::new(Some(2), None)])?;
+    assert_eq!(dir.index_contents("file"), "b\nd\n");
+    assert_eq!(repo.history_entries().len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn unstage_lines() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", b"a\nb\nc\n");
+    dir.commit_all();
+    dir.set_file("file", b"a\nB\nc\nd\n");
+    dir.add("file");
+
+    let status = repo.status()?;
+    let details = repo.staged_details(status[0].staged().unwrap())?;
+
+    repo.unstage_lines(&details, &[LineId::new(None, Some(4))])?;
+    assert_eq!(dir.index_contents("file"), "a\nB\nc\n");
+
+    repo.undo()?;
+    assert_eq!(dir.index_contents("file"), "a\nB\nc\nd\n");
+
+    Ok(())
+}
+
+#[test]
+fn status_splits_staged_and_unstaged() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", NUMBERS);
+    dir.commit_all();
+    dir.set_file("file", NUMBERS_ENDS_CHANGED);
+    dir.set_file("untracked", b"untracked");
+
+    let status = repo.status()?;
+    let meta = status[0].unstaged().unwrap().clone();
+    let details = repo.unstaged_details(&meta)?;
+    repo.stage_hunk(&details, &details.hunks()[0])?;
+
+    let status = repo.status()?;
+    assert_matches!(status.as_slice(), [file, untracked] if
+        file.path() == Path::new("file") &&
+        matches!(file.staged(), Some(Meta::Modified { .. })) &&
+        matches!(file.unstaged(), Some(Meta::Modified { .. })) &&
+        untracked.staged().is_none() &&
+        matches!(untracked.unstaged(), Some(Meta::Untracked(_)))
+    );
+
+    let staged = repo.staged_details(status[0].staged().unwrap())?;
+    assert_eq!(staged.hunks().len(), 1);
+    assert_eq!(staged.hunks()[0].old_start(), 1);
+
+    let unstaged = repo.unstaged_details(status[0].unstaged().unwrap())?;
+    assert_eq!(unstaged.hunks().len(), 1);
+    assert_eq!(unstaged.hunks()[0].new_start(), 9);
+
+    Ok(())
+}
+
+#[test]
+fn discard_file() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", b"committed");
+    dir.commit_all();
+    dir.set_file("file", b"staged");
+    dir.add("file");
+    dir.set_file("file", b"unstaged");
+
+    let file = repo.status()?[0].unstaged().unwrap().file().clone();
+
+    repo.discard_file(&file, DiscardTo::Index)?;
+    assert_eq!(fs::read(dir.path().join("file")).unwrap(), b"staged");
+
+    repo.undo()?;
+    assert_eq!(fs::read(dir.path().join("file")).unwrap(), b"unstaged");
+
+    repo.discard_file(&file, DiscardTo::Head)?;
+    assert_eq!(fs::read(dir.path().join("file")).unwrap(), b"committed");
+
+    Ok(())
+}
+
+#[test]
+fn discard_untracked_file() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("untracked", b"precious");
+
+    let file = repo.status()?[0].unstaged().unwrap().file().clone();
+
+    repo.discard_file(&file, DiscardTo::Index)?;
+    assert!(!dir.path().join("untracked").exists());
+
+    repo.undo()?;
+    assert_eq!(fs::read(dir.path().join("untracked")).unwrap(), b"precious");
+
+    repo.redo()?;
+    assert!(!dir.path().join("untracked").exists());
+
+    Ok(())
+}
+
+#[test]
+fn discard_hunk() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", NUMBERS);
+    dir.commit_all();
+    dir.set_file("file", NUMBERS_ENDS_CHANGED);
+
+    let meta = repo.status()?[0].unstaged().unwrap().clone();
+    let details = repo.unstaged_details(&meta)?;
+    repo.discard_hunk(&details, &details.hunks()[0])?;
+    assert_eq!(
+        fs::read_to_string(dir.path().join("file")).unwrap(),
+        "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\ntwelve\n"
+    );
+
+    repo.undo()?;
+    assert_eq!(
+        fs::read(dir.path().join("file")).unwrap(),
+        NUMBERS_ENDS_CHANGED
+    );
+
+    Ok(())
+}
+
+#[test]
+fn discard_hunk_without_trailing_newline() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", b"a\nb");
+    dir.commit_all();
+    dir.set_file("file", b"a\nc");
+
+    let meta = repo.status()?[0].unstaged().unwrap().clone();
+    let details = repo.unstaged_details(&meta)?;
+    repo.discard_hunk(&details, &details.hunks()[0])?;
+    assert_eq!(fs::read(dir.path().join("file")).unwrap(), b"a\nb");
+
+    repo.undo()?;
+    assert_eq!(fs::read(dir.path().join("file")).unwrap(), b"a\nc");
+
+    Ok(())
+}
+
+#[test]
+fn commit() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", b"contents");
+    dir.add("file");
+
+    repo.commit("First commit", &CommitOptions::new())?;
+    let first = dir.rev_parse("HEAD").unwrap();
+    assert!(repo.status()?.is_empty());
+
+    repo.undo()?;
+    assert_eq!(dir.rev_parse("HEAD"), None);
+    assert_matches!(repo.status()?.as_slice(), [status] if
+        matches!(status.staged(), Some(Meta::Added(_)))
+    );
+
+    repo.redo()?;
+    assert_eq!(dir.rev_parse("HEAD"), Some(first.clone()));
+
+    dir.set_file("file", b"changed");
+    dir.add("file");
+    repo.commit("Second commit", &CommitOptions::new())?;
+    assert_eq!(dir.rev_parse("HEAD^"), Some(first.clone()));
+
+    repo.undo()?;
+    assert_eq!(dir.rev_parse("HEAD"), Some(first));
+
+    Ok(())
+}
+
+#[test]
+fn commit_nothing_errors() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", b"contents");
+    dir.commit_all();
+
+    assert_matches!(
+        repo.commit("Empty", &CommitOptions::new()),
+        Err(idgit::Error::NothingToCommit)
+    );
+    repo.commit("Empty", CommitOptions::new().allow_empty(true))?;
+
+    Ok(())
+}
+
+#[test]
+fn amend_and_reword() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", b"contents");
+    dir.commit_all();
+    let original = dir.rev_parse("HEAD").unwrap();
+
+    dir.set_file("other", b"forgotten");
+    dir.add("other");
+    repo.amend(None, &CommitOptions::new())?;
+    assert_ne!(dir.rev_parse("HEAD").unwrap(), original);
+    assert_eq!(dir.message("HEAD"), "Make some change");
+    assert_eq!(dir.rev_parse("HEAD~1"), None);
+    assert!(repo.status()?.is_empty());
+
+    repo.reword("Better message", &CommitOptions::new())?;
+    assert_eq!(dir.message("HEAD"), "Better message");
+
+    repo.undo()?;
+    assert_eq!(dir.message("HEAD"), "Make some change");
+
+    repo.undo()?;
+    assert_eq!(dir.rev_parse("HEAD").unwrap(), original);
+
+    Ok(())
+}
+
+#[test]
+fn amend_merge_errors() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", b"contents");
+    dir.commit_all();
+
+    let path = dir.path_str();
+    (run_cmd! {
+        cd $path;
+        git checkout -q -b other;
+        git commit -q --allow-empty -m "On other";
+        git checkout -q -;
+        git commit -q --allow-empty -m "On main";
+        git merge -q --no-edit other;
+    })
+    .unwrap();
+    let merge = dir.rev_parse("HEAD").unwrap();
+
+    assert_matches!(
+        repo.reword("Reworded", &CommitOptions::new()),
+        Err(idgit::Error::HeadIsMerge(id)) if id.to_string() == merge
+    );
+    repo.reword("Reworded", CommitOptions::new().allow_merge(true))?;
+    assert_eq!(dir.message("HEAD"), "Reworded");
+
+    Ok(())
+}
+
+#[test]
+fn history_persists_across_sessions() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+
+    dir.set_file("f", b"contents");
+
+    {
+        let mut repo = Repo::open(dir.path())?;
+        let status = repo.status()?;
+        repo.stage_file(status[0].unstaged().unwrap().file())?;
+    }
+
+    let mut repo = Repo::open(dir.path())?;
+    assert!(repo.can_undo());
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Added(_)]
+    );
+
+    repo.undo()?;
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Untracked(_)]
+    );
+
+    let repo = Repo::open(dir.path())?;
+    assert!(!repo.can_undo());
+    assert!(repo.can_redo());
+
+    Ok(())
+}
+
+#[test]
+fn history_entries_and_goto() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("a", b"a");
+    dir.set_file("b", b"b");
+    let status = repo.status()?;
+    repo.stage_file(status[0].unstaged().unwrap().file())?;
+    repo.stage_file(status[1].unstaged().unwrap().file())?;
+    repo.commit("Add a and b\n\nWith a body", &CommitOptions::default())?;
+
+    let describe = |repo: &Repo| {
+        repo.history_entries()
+            .iter()
+            .map(|entry| (entry.description().to_owned(), entry.is_current()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        describe(&repo),
+        [
+            ("Staged a".to_owned(), false),
+            ("Staged b".to_owned(), false),
+            ("Committed \"Add a and b\"".to_owned(), true),
+        ]
+    );
+
+    repo.goto(0, 1)?;
+    assert_eq!(dir.rev_parse("HEAD"), None);
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Added(_), Meta::Untracked(_)]
+    );
+    assert!(describe(&repo)[0].1);
+
+    repo.goto(0, 0)?;
+    assert!(describe(&repo).iter().all(|(_, current)| !current));
+
+    repo.goto(0, 3)?;
+    assert!(dir.rev_parse("HEAD").is_some());
+    assert!(describe(&repo)[2].1);
+
+    assert!(repo.goto(0, 4).is_err());
+    assert!(repo.goto(1, 0).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn new_change_after_undo_branches() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("a", b"a");
+    dir.set_file("b", b"b");
+    dir.set_file("c", b"c");
+    let status = repo.status()?;
+    repo.stage_file(status[0].unstaged().unwrap().file())?;
+    repo.stage_file(status[1].unstaged().unwrap().file())?;
+    repo.undo()?;
+    repo.stage_file(status[2].unstaged().unwrap().file())?;
+    assert!(!repo.can_redo());
+
+    let descriptions = |entries: &[HistoryEntry]| {
+        entries
+            .iter()
+            .map(|entry| entry.description().to_owned())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        descriptions(&repo.history_entries()),
+        ["Staged a", "Staged c"]
+    );
+
+    let branches = repo.history_branches();
+    assert_eq!(branches.len(), 2);
+    assert_eq!(branches[0].parent(), None);
+    assert_eq!(
+        descriptions(branches[0].entries()),
+        ["Staged a", "Staged b"]
+    );
+    assert!(!branches[0].entries()[1].is_applied());
+    assert_eq!(branches[1].parent(), Some((0, 1)));
+    assert_eq!(descriptions(branches[1].entries()), ["Staged c"]);
+    assert!(branches[1].is_current());
+    assert!(branches[1].entries()[0].is_current());
+
+    // Back to the abandoned branch
+    repo.goto(0, 2)?;
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Added(_), Meta::Added(_), Meta::Untracked(_)]
+    );
+    assert_eq!(
+        descriptions(&repo.history_entries()),
+        ["Staged a", "Staged b"]
+    );
+
+    // Undoing past the fork then redoing stays on the branch we're on
+    repo.undo()?;
+    repo.undo()?;
+    repo.redo()?;
+    repo.redo()?;
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Added(_), Meta::Added(_), Meta::Untracked(_)]
+    );
+
+    repo.goto(1, 1)?;
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Added(_), Meta::Untracked(_), Meta::Added(_)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn transaction_is_one_entry() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("a", b"a");
+    dir.set_file("b", b"b");
+    let status = repo.status()?;
+    repo.transaction("Staged a and b", |repo| {
+        repo.stage_file(status[0].unstaged().unwrap().file())?;
+        repo.stage_file(status[1].unstaged().unwrap().file())
+    })?;
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Added(_), Meta::Added(_)]
+    );
+
+    let entries = repo.history_entries();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].description(), "Staged a and b");
+
+    repo.undo()?;
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Untracked(_), Meta::Untracked(_)]
+    );
+    assert!(!repo.can_undo());
+
+    repo.redo()?;
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Added(_), Meta::Added(_)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn failed_transaction_rolls_back() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("a", b"a");
+    let status = repo.status()?;
+    let result = repo.transaction("Stage and amend", |repo| {
+        repo.stage_file(status[0].unstaged().unwrap().file())?;
+        assert_matches!(repo.undo(), Err(idgit::Error::UndoInTransaction));
+        // Nothing to amend yet
+        repo.amend(None, &CommitOptions::default())
+    });
+    assert_matches!(result, Err(idgit::Error::UnbornHead));
+
+    assert_matches!(
+        repo.uncommitted_files(&StatusOptions::new())?.as_slice(),
+        [Meta::Untracked(_)]
+    );
+    assert!(!repo.can_undo());
+    assert!(repo.history_entries().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn panicking_transaction_rolls_back() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("a", b"a");
+    dir.set_file("b", b"b");
+    let status = repo.status()?;
+    repo.stage_file(status[0].unstaged().unwrap().file())?;
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        repo.transaction("Stage and panic", |repo| -> Result<()> {
+            repo.stage_file(status[1].unstaged().unwrap().file())?;
+            panic!("Partway through");
+        })
+    }));
+    assert!(result.is_err());
+
+    let staged = |repo: &Repo| -> Result<Vec<Status>> {
+        let mut status = repo.status()?;
+        status.retain(|file| file.staged().is_some());
+        Ok(status)
+    };
+    assert_matches!(staged(&repo)?.as_slice(), [a] if a.path() == Path::new("a"));
+    assert_eq!(repo.history_entries().len(), 1);
+    repo.undo()?;
+    assert!(staged(&repo)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn subscription_sees_changes() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let repo = Repo::open(dir.path())?;
+    let subscription = repo.subscribe()?;
+
+    dir.set_file("a", b"a");
+    let status = subscription
+        .recv_timeout(Duration::from_secs(5))
+        .expect("Change seen")?;
+    assert_eq!(status.len(), 1);
+    assert_eq!(status[0].path(), Path::new("a"));
+
+    // The first change may be seen in more than one burst
+    dir.add("a");
+    let mut status = vec![];
+    while !matches!(
+        status.first().and_then(Status::staged),
+        Some(Meta::Added(_))
+    ) {
+        status = subscription
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Change to index seen")?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn create_delete_and_rename_branches() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"a");
+    dir.commit_all();
+    let head = dir.rev_parse("HEAD").unwrap();
+    let mut repo = Repo::open(dir.path())?;
+
+    repo.create_branch("feature", None)?;
+    assert_eq!(dir.rev_parse("feature").unwrap(), head);
+    assert_matches!(
+        repo.create_branch("feature", None),
+        Err(idgit::Error::BranchExists(name)) if name == "feature"
+    );
+    assert_matches!(
+        repo.create_branch("bad..name", None),
+        Err(idgit::Error::InvalidBranchName(_))
+    );
+
+    repo.rename_branch("feature", "renamed")?;
+    assert_eq!(dir.rev_parse("feature"), None);
+    assert_eq!(dir.rev_parse("renamed").unwrap(), head);
+
+    repo.delete_branch("renamed")?;
+    assert_eq!(dir.rev_parse("renamed"), None);
+    let branches = repo.branches()?;
+    assert_eq!(branches.len(), 1);
+    assert!(branches[0].is_head());
+
+    repo.undo()?;
+    assert_eq!(dir.rev_parse("renamed").unwrap(), head);
+    repo.undo()?;
+    assert_eq!(dir.rev_parse("feature").unwrap(), head);
+    assert_eq!(dir.rev_parse("renamed"), None);
+    repo.undo()?;
+    assert_eq!(dir.rev_parse("feature"), None);
+
+    repo.redo()?;
+    repo.redo()?;
+    repo.redo()?;
+    assert_eq!(dir.rev_parse("renamed"), None);
+    assert_eq!(
+        repo.history_entries()
+            .iter()
+            .map(|entry| entry.description().to_owned())
+            .collect::<Vec<_>>(),
+        vec![
+            "Created branch feature",
+            "Renamed branch feature to renamed",
+            "Deleted branch renamed"
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn delete_branch_removes_its_config() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"a");
+    dir.commit_all();
+    let mut repo = Repo::open(dir.path())?;
+
+    repo.create_branch("feature", None)?;
+    let path = dir.path_str();
+    (run_cmd! {
+        cd $path;
+        git config branch.feature.remote origin;
+        git config branch.feature.merge refs/heads/feature;
+        git config --add branch.feature.merge refs/heads/other;
+    })
+    .unwrap();
+
+    repo.delete_branch("feature")?;
+    assert!(dir.config_values("branch.feature.remote").is_empty());
+    assert!(dir.config_values("branch.feature.merge").is_empty());
+
+    // A new branch of the same name doesn't pick up the old upstream
+    repo.create_branch("feature", None)?;
+    assert!(dir.config_values("branch.feature.remote").is_empty());
+
+    repo.undo()?;
+    repo.undo()?;
+    assert_eq!(dir.config_values("branch.feature.remote"), vec!["origin"]);
+    assert_eq!(
+        dir.config_values("branch.feature.merge"),
+        vec!["refs/heads/feature", "refs/heads/other"]
+    );
+
+    repo.redo()?;
+    assert!(dir.config_values("branch.feature.merge").is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn delete_current_branch_errors() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.change_something();
+    dir.commit_all();
+    let mut repo = Repo::open(dir.path())?;
+
+    let current = repo.branches()?[0].name().to_owned();
+    assert_matches!(
+        repo.delete_branch(&current),
+        Err(idgit::Error::DeleteCurrentBranch(_))
+    );
+    assert_matches!(
+        repo.delete_branch("missing"),
+        Err(idgit::Error::BranchNotFound(_))
+    );
+    assert!(!repo.can_undo());
+
+    Ok(())
+}
+
+#[test]
+fn checkout_branch() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"first");
+    dir.commit_all();
+    let mut repo = Repo::open(dir.path())?;
+    let original = repo.branches()?[0].name().to_owned();
+
+    repo.create_branch("other", None)?;
+    dir.set_file("a", b"second");
+    dir.commit_all();
+
+    repo.checkout_branch("other")?;
+    assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"first");
+    assert_eq!(dir.index_contents("a"), "first");
+    assert!(repo
+        .branches()?
+        .iter()
+        .any(|b| b.name() == "other" && b.is_head()));
+
+    repo.undo()?;
+    assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"second");
+    assert!(repo
+        .branches()?
+        .iter()
+        .any(|b| b.name() == original && b.is_head()));
+
+    Ok(())
+}
+
+#[test]
+fn checkout_branch_over_changes_errors() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"first");
+    dir.commit_all();
+    let mut repo = Repo::open(dir.path())?;
+
+    repo.create_branch("other", None)?;
+    dir.set_file("a", b"second");
+    dir.commit_all();
+    dir.set_file("a", b"uncommitted");
+
+    assert_matches!(
+        repo.checkout_branch("other"),
+        Err(idgit::Error::CheckoutConflicts(paths)) if paths == vec![Path::new("a").to_path_buf()]
+    );
+    assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"uncommitted");
+    assert_eq!(repo.history_entries().len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn stash_push_and_pop() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"a");
+    dir.set_file("b", b"b");
+    dir.commit_all();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("a", b"a staged");
+    dir.add("a");
+    dir.set_file("a", b"a unstaged");
+    dir.set_file("b", b"b unstaged");
+    dir.set_file("untracked", b"untracked");
+
+    repo.stash_push(Some("Half done"), &StashScope::Tracked)?;
+    assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"a");
+    assert_eq!(dir.index_contents("a"), "a");
+    assert_eq!(fs::read(dir.path().join("b")).unwrap(), b"b");
+    assert!(dir.path().join("untracked").exists());
+
+    let stashes = repo.stashes()?;
+    assert_eq!(stashes.len(), 1);
+    assert!(stashes[0].message().ends_with(": Half done"));
+    assert_eq!(
+        dir.rev_parse("stash@{0}"),
+        Some(stashes[0].id().to_string())
+    );
+    assert_eq!(repo.stash_show(0)?.len(), 2);
+
+    repo.stash_pop(0)?;
+    assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"a unstaged");
+    assert_eq!(dir.index_contents("a"), "a staged");
+    assert_eq!(fs::read(dir.path().join("b")).unwrap(), b"b unstaged");
+    assert!(repo.stashes()?.is_empty());
+    assert_eq!(
+        repo.history_entries()
+            .iter()
+            .map(|entry| entry.description().to_owned())
+            .collect::<Vec<_>>(),
+        vec!["Stashed \"Half done\"", "Popped stash@{0}"]
+    );
+
+    // Undoing the pop puts the stash back and the files as they were
+    repo.undo()?;
+    assert_eq!(repo.stashes()?.len(), 1);
+    assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"a");
+
+    repo.undo()?;
+    assert!(repo.stashes()?.is_empty());
+    assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"a unstaged");
+    assert_eq!(dir.index_contents("a"), "a staged");
+
+    Ok(())
+}
+
+#[test]
+fn stash_staged_and_files() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"a");
+    dir.set_file("b", b"b");
+    dir.commit_all();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("a", b"a staged");
+    dir.add("a");
+    dir.set_file("b", b"b unstaged");
+    repo.stash_push(None, &StashScope::Staged)?;
+    assert_eq!(dir.index_contents("a"), "a");
+    assert_eq!(fs::read(dir.path().join("b")).unwrap(), b"b unstaged");
+
+    dir.set_file("untracked", b"untracked");
+    let files = repo.uncommitted_files(&StatusOptions::new())?;
+    let untracked = files
+        .iter()
+        .find(|meta| matches!(meta, Meta::Untracked(_)))
+        .unwrap()
+        .file()
+        .clone();
+    repo.stash_push(None, &StashScope::Files(vec![untracked]))?;
+    assert!(!dir.path().join("untracked").exists());
+    assert_eq!(fs::read(dir.path().join("b")).unwrap(), b"b unstaged");
+    assert_eq!(repo.stashes()?.len(), 2);
+
+    assert_matches!(
+        repo.stash_push(None, &StashScope::Staged),
+        Err(idgit::Error::NothingToStash)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn stash_staged_keeps_unstaged_changes() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", NUMBERS);
+    dir.commit_all();
+    let mut repo = Repo::open(dir.path())?;
+
+    let staged = "one\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+    dir.set_file("a", staged.as_bytes());
+    dir.add("a");
+    dir.set_file("a", NUMBERS_ENDS_CHANGED);
+
+    repo.stash_push(None, &StashScope::Staged)?;
+    assert_eq!(dir.index_contents("a").as_bytes(), NUMBERS);
+    assert_eq!(
+        fs::read(dir.path().join("a")).unwrap(),
+        b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\ntwelve\n"
+    );
+
+    let shown = repo.stash_show(0)?;
+    assert_eq!(shown.len(), 1);
+    assert_eq!(
+        lines_of(&shown[0], git2::DiffLineType::Addition),
+        vec![b"one\n".as_ref()]
+    );
+
+    repo.undo()?;
+    assert_eq!(dir.index_contents("a"), staged);
+    assert_eq!(
+        fs::read(dir.path().join("a")).unwrap(),
+        NUMBERS_ENDS_CHANGED
+    );
+
+    // Popping merges the stashed change back in with the unstaged one
+    repo.redo()?;
+    repo.stash_pop(0)?;
+    assert_eq!(dir.index_contents("a"), staged);
+    assert_eq!(
+        fs::read(dir.path().join("a")).unwrap(),
+        NUMBERS_ENDS_CHANGED
+    );
+
+    Ok(())
+}
+
+#[test]
+fn stash_files_matches_paths_exactly() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a[1].txt", b"a");
+    dir.set_file("a1.txt", b"a");
+    dir.commit_all();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("a[1].txt", b"changed");
+    dir.set_file("a1.txt", b"changed");
+    let status = repo.status()?;
+    let file = status
+        .iter()
+        .find(|status| status.path() == Path::new("a[1].txt"))
+        .unwrap()
+        .unstaged()
+        .unwrap()
+        .file()
+        .clone();
+
+    repo.stash_push(None, &StashScope::Files(vec![file]))?;
+    assert_eq!(fs::read(dir.path().join("a[1].txt")).unwrap(), b"a");
+    assert_eq!(fs::read(dir.path().join("a1.txt")).unwrap(), b"changed");
+
+    Ok(())
+}
+
+#[test]
+fn stash_drop_is_undoable() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"a");
+    dir.commit_all();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("a", b"first");
+    repo.stash_push(Some("first"), &StashScope::Tracked)?;
+    dir.set_file("a", b"second");
+    repo.stash_push(Some("second"), &StashScope::Tracked)?;
+    let before: Vec<_> = repo.stashes()?.iter().map(|stash| stash.id()).collect();
+    let reflog = || {
+        let git = git2::Repository::open(dir.path()).unwrap();
+        let reflog = git.reflog("refs/stash").unwrap();
+        reflog
+            .iter()
+            .map(|entry| {
+                (
+                    entry.id_new(),
+                    entry.message().map(str::to_owned),
+                    entry.committer().when().seconds(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let reflog_before = reflog();
+
+    repo.stash_drop(1)?;
+    assert_eq!(repo.stashes()?.len(), 1);
+    assert_eq!(repo.stashes()?[0].id(), before[0]);
+    assert_eq!(reflog(), reflog_before[..1]);
+    assert_matches!(repo.stash_drop(5), Err(idgit::Error::StashNotFound(5)));
+
+    repo.undo()?;
+    let after: Vec<_> = repo.stashes()?.iter().map(|stash| stash.id()).collect();
+    assert_eq!(after, before);
+    assert_eq!(dir.rev_parse("stash@{1}"), Some(before[1].to_string()));
+    assert_eq!(reflog(), reflog_before);
+
+    Ok(())
+}
+
+#[test]
+fn stash_apply_merges_with_later_commits() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", NUMBERS);
+    dir.commit_all();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("a", b"one\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n");
+    repo.stash_push(None, &StashScope::Tracked)?;
+    let committed = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\ntwelve\n";
+    dir.set_file("a", committed.as_bytes());
+    dir.commit_all();
+
+    repo.stash_apply(0)?;
+    assert_eq!(
+        fs::read(dir.path().join("a")).unwrap(),
+        NUMBERS_ENDS_CHANGED
+    );
+    assert_eq!(dir.index_contents("a"), committed);
+
+    repo.undo()?;
+    assert_eq!(
+        fs::read(dir.path().join("a")).unwrap(),
+        committed.as_bytes()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn stash_apply_over_changes_errors() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"a");
+    dir.commit_all();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("a", b"stashed");
+    repo.stash_push(None, &StashScope::Tracked)?;
+    dir.set_file("a", b"changed since");
+
+    assert_matches!(
+        repo.stash_apply(0),
+        Err(idgit::Error::StashConflicts(paths)) if paths == vec![Path::new("a").to_path_buf()]
+    );
+    assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"changed since");
+
+    Ok(())
+}
+
+#[test]
+fn log_lists_and_filters_commits() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"a");
+    dir.commit_all();
+    dir.set_file("b", b"b");
+    dir.add("b");
+    let path = dir.path_str();
+    (run_cmd! {
+        cd $path;
+        git -c user.name=Someone -c user.email=someone@idgit.test commit -q
+            "--date=2005-04-07T22:13:13" -m "Add b" -m "Fixes the thing";
+        git tag v1;
+    })
+    .unwrap();
+    dir.set_file("a", b"changed");
+    dir.commit_all();
+
+    let repo = Repo::open(dir.path())?;
+    let log = repo.log(&LogOptions::new())?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(log.len(), 3);
+    assert_eq!(log[0].id().to_string(), dir.rev_parse("HEAD").unwrap());
+    assert_eq!(log[0].parents(), &[log[1].id()]);
+    assert!(log[0].refs().contains(&"HEAD".to_owned()));
+    assert_eq!(log[1].refs(), &["v1".to_owned()]);
+    assert_eq!(log[1].summary(), "Add b");
+    assert_eq!(log[1].author_name(), "Someone");
+    assert!(log[2].parents().is_empty());
+
+    let page = repo
+        .log(&LogOptions::new())?
+        .skip(1)
+        .take(1)
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(page[0].id(), log[1].id());
+
+    let touching_a = repo
+        .log(LogOptions::new().path("a"))?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        touching_a.iter().map(LogCommit::id).collect::<Vec<_>>(),
+        vec![log[0].id(), log[2].id()]
+    );
+
+    let by_someone = repo
+        .log(LogOptions::new().author("idgit.test"))?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(by_someone.len(), 1);
+
+    let mentioning = repo
+        .log(LogOptions::new().message("the thing"))?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(mentioning.len(), 1);
+
+    let in_future = repo
+        .log(LogOptions::new().since(SystemTime::now() + Duration::from_secs(3600)))?
+        .count();
+    assert_eq!(in_future, 0);
+
+    // Filtered on when each was committed, even if authored long before
+    let since = SystemTime::now() - Duration::from_secs(3600);
+    let recent = repo
+        .log(LogOptions::new().since(since))?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(recent.len(), 3);
+    assert!(recent[1].commit_time() >= since);
+    assert!(recent[1].author_time() < since);
+
+    let from_tag = repo
+        .log(LogOptions::new().from("v1").order(LogOrder::Topological))?
+        .count();
+    assert_eq!(from_tag, 2);
+
+    Ok(())
+}
+
+#[test]
+fn log_of_empty_repo_is_empty() -> Result<()> {
+    init_logs();
+    let dir = SampleRepoDir::new();
+    let repo = Repo::open(dir.path())?;
+    assert_eq!(repo.log(&LogOptions::new())?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn graph_of_branch_and_merge() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("file", b"contents");
+    dir.commit_all();
+
+    let path = dir.path_str();
+    (run_cmd! {
+        cd $path;
+        git checkout -q -b other;
+        git commit -q --allow-empty -m "On other";
+        git checkout -q -;
+        git commit -q --allow-empty -m "On main";
+        git merge -q --no-edit other;
+    })
+    .unwrap();
+
+    let repo = Repo::open(dir.path())?;
+    let log = repo
+        .log(LogOptions::new().order(LogOrder::Topological))?
+        .collect::<Result<Vec<_>>>()?;
+    let graph = log.iter().collect::<Graph>();
+    let rows = graph.rows();
+    assert_eq!(rows.len(), 4);
+
+    // The merge, with the branch merged in opening a second lane
+    assert_eq!(rows[0].column(), 0);
+    assert_eq!(rows[0].parents(), &[0, 1]);
+    // Both sides of the merge, each passing the other by
+    assert_eq!(rows[1].through().len(), 1);
+    assert_eq!(rows[2].through().len(), 1);
+    // The commit the branch started from, where the lanes meet again
+    assert_eq!(rows[3].id(), log[3].id());
+    assert!(rows[3].through().is_empty());
+    assert!(rows[3].parents().is_empty());
+    assert_eq!(graph.width(), 2);
+
+    Ok(())
+}
+
+fn oid(n: u8) -> git2::Oid {
+    git2::Oid::from_bytes(&[n; 20]).unwrap()
+}
+
+#[test]
+fn graph_of_octopus_merge() {
+    let (merge, a, b, c, root) = (oid(1), oid(2), oid(3), oid(4), oid(5));
+    let mut graph = Graph::new();
+    graph.push(merge, &[a, b, c]);
+    graph.push(a, &[root]);
+    graph.push(b, &[root]);
+    graph.push(c, &[root]);
+    graph.push(root, &[]);
+    let rows = graph.rows();
+
+    assert_eq!(rows[0].parents(), &[0, 1, 2]);
+    assert_eq!(rows[0].width(), 3);
+
+    assert_eq!(rows[1].column(), 0);
+    assert_eq!(rows[1].through(), &[1, 2]);
+    assert_eq!(rows[1].parents(), &[0]);
+
+    // Already waited for in the first lane, so these lanes end here
+    assert_eq!(rows[2].column(), 1);
+    assert_eq!(rows[2].parents(), &[0]);
+    assert_eq!(rows[3].column(), 2);
+    assert_eq!(rows[3].through(), &[0]);
+    assert_eq!(rows[3].parents(), &[0]);
+    assert_eq!(rows[3].width(), 3);
+
+    assert_eq!(rows[4].column(), 0);
+    assert!(rows[4].merged().is_empty());
+    assert_eq!(rows[4].width(), 1);
+}
+
+#[test]
+fn graph_of_separate_tips_merges_lanes() {
+    let (first, second, base) = (oid(1), oid(2), oid(3));
+    let mut graph = Graph::new();
+    graph.push(first, &[base]);
+    graph.push(second, &[base]);
+    graph.push(base, &[]);
+    let rows = graph.rows();
+
+    assert_eq!(rows[1].column(), 1);
+    assert_eq!(rows[1].through(), &[0]);
+    assert_eq!(rows[1].parents(), &[0]);
+    assert_eq!(rows[2].column(), 0);
+    assert_eq!(rows[2].width(), 1);
+}
+
+#[test]
+fn graph_of_two_lines_meeting() {
+    let (left, right, left_base, right_base, root) = (oid(1), oid(2), oid(3), oid(4), oid(5));
+    let mut graph = Graph::new();
+    graph.push(left, &[left_base]);
+    graph.push(right, &[right_base]);
+    graph.push(left_base, &[root]);
+    graph.push(right_base, &[root]);
+    graph.push(root, &[]);
+    let rows = graph.rows();
+
+    assert_eq!(rows[2].column(), 0);
+    assert_eq!(rows[2].through(), &[1]);
+    // Joins the first lane, which was already waiting for the root
+    assert_eq!(rows[3].column(), 1);
+    assert_eq!(rows[3].parents(), &[0]);
+    assert!(rows[4].merged().is_empty());
+}
+
+#[test]
+fn graph_reuses_lanes() {
+    // Twenty feature branches, each merged into main one after another
+    let mut graph = Graph::new();
+    let main = |n: u8| oid(n * 2 + 1);
+    let feature = |n: u8| oid(n * 2 + 2);
+    for n in (1..=20).rev() {
+        assert_eq!(graph.push(main(n), &[main(n - 1), feature(n)]).column(), 0);
+        assert_eq!(graph.push(feature(n), &[main(n - 1)]).column(), 1);
+    }
+    graph.push(main(0), &[]);
+
+    assert_eq!(graph.rows().len(), 41);
+    assert_eq!(graph.width(), 2);
+}
+
+fn lines_of(details: &idgit::diff::Details, origin: git2::DiffLineType) -> Vec<&[u8]> {
+    details
+        .lines()
+        .filter(|line| line.origin() == origin)
+        .map(idgit::diff::Line::content)
+        .collect()
+}
+
+#[test]
+fn diff_between_commits() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", NUMBERS);
+    dir.set_file("b", b"b");
+    dir.commit_all();
+    dir.set_file("a", NUMBERS_ENDS_CHANGED);
+    fs::remove_file(dir.path().join("b")).unwrap();
+    dir.set_file("c", b"c");
+    dir.commit_all();
+
+    let repo = Repo::open(dir.path())?;
+    let diff = repo.diff(
+        &Revision::commit("HEAD~1"),
+        &Revision::commit("HEAD"),
+        &DiffOptions::new(),
+    )?;
+    assert_eq!(diff.len(), 3);
+    assert_matches!(diff[0].meta(), Meta::Modified { new, .. } if new.rel_path() == Some(Path::new("a")));
+    assert_eq!(diff[0].hunks().len(), 2);
+    assert_matches!(diff[1].meta(), Meta::Deleted(_));
+    assert_matches!(diff[2].meta(), Meta::Added(_));
+    assert_eq!(
+        lines_of(&diff[2], git2::DiffLineType::Addition),
+        vec![b"c".as_ref()]
+    );
+
+    let head = dir.rev_parse("HEAD").unwrap();
+    let reversed = repo.diff(
+        &Revision::from(git2::Oid::from_str(&head).unwrap()),
+        &Revision::commit("HEAD~1"),
+        &DiffOptions::new(),
+    )?;
+    assert_matches!(reversed[1].meta(), Meta::Added(_));
+    assert_matches!(reversed[2].meta(), Meta::Deleted(_));
+
+    let no_context = repo.diff(
+        &Revision::commit("HEAD~1"),
+        &Revision::commit("HEAD"),
+        DiffOptions::new().context_lines(0).pathspec("a"),
+    )?;
+    assert_eq!(no_context.len(), 1);
+    assert_eq!(no_context[0].lines().count(), 4);
+
+    Ok(())
+}
+
+#[test]
+fn diff_with_index_and_workdir() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", NUMBERS);
+    dir.commit_all();
+    dir.set_file("a", NUMBERS_ENDS_CHANGED);
+    dir.add("a");
+    dir.set_file("a", b"unstaged\n");
+    dir.set_file("untracked", b"new");
+
+    let repo = Repo::open(dir.path())?;
+    let opts = DiffOptions::new();
+    let staged = repo.diff(&Revision::commit("HEAD"), &Revision::Index, &opts)?;
+    assert_eq!(staged.len(), 1);
+    assert!(lines_of(&staged[0], git2::DiffLineType::Addition).contains(&b"twelve\n".as_ref()));
+
+    let unstaged = repo.diff(&Revision::Index, &Revision::Workdir, &opts)?;
+    assert_eq!(unstaged.len(), 1);
+    assert_eq!(
+        lines_of(&unstaged[0], git2::DiffLineType::Addition),
+        vec![b"unstaged\n".as_ref()]
+    );
+
+    let reversed = repo.diff(&Revision::Workdir, &Revision::Index, &opts)?;
+    assert_eq!(
+        lines_of(&reversed[0], git2::DiffLineType::Deletion),
+        vec![b"unstaged\n".as_ref()]
+    );
+
+    let everything = repo.diff(
+        &Revision::commit("HEAD"),
+        &Revision::Workdir,
+        DiffOptions::new().include_untracked(true),
+    )?;
+    assert_eq!(everything.len(), 2);
+    assert_matches!(everything[1].meta(), Meta::Untracked(_));
+
+    assert!(repo
+        .diff(&Revision::Index, &Revision::Index, &opts)?
+        .is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn diff_from_unborn_head() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", b"a");
+    dir.add("a");
+
+    let repo = Repo::open(dir.path())?;
+    let staged = repo.diff(
+        &Revision::commit("HEAD"),
+        &Revision::Index,
+        &DiffOptions::new(),
+    )?;
+    assert_eq!(staged.len(), 1);
+    assert_matches!(staged[0].meta(), Meta::Added(_));
+
+    assert_matches!(
+        repo.diff(&Revision::commit("nope"), &Revision::Index, &DiffOptions::new()),
+        Err(idgit::Error::RevisionNotFound(rev)) if rev == "nope"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn worker_answers_files_and_details() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    dir.set_file("a", NUMBERS);
+    dir.commit_all();
+    dir.set_file("a", NUMBERS_ENDS_CHANGED);
+
+    let repo = Repo::open(dir.path())?;
+    let (tx, responses) = mpsc::channel();
+    let worker = repo.worker(move |response| tx.send(response).unwrap())?;
+
+    worker.files(&StatusOptions::new());
+    let files = match responses.recv_timeout(Duration::from_secs(5)).unwrap() {
+        WorkerResponse::Files(files) => files?,
+        other => panic!("Expected files, got {:?}", other),
+    };
+    assert_matches!(files.as_slice(), [Meta::Modified { .. }]);
+
+    // Asking twice for an unchanged file is answered from the cache
+    for hits in 0..2 {
+        worker.details(&files[0]);
+        match responses.recv_timeout(Duration::from_secs(5)).unwrap() {
+            WorkerResponse::Details(_, details) => assert_eq!(details?.hunks().len(), 2),
+            other => panic!("Expected details, got {:?}", other),
+        }
+        assert_eq!(worker.cache_hits(), hits);
+    }
+
+    // A change to the file is noticed, not hidden by the cache
+    dir.set_file("a", b"1\n");
+    worker.details(&files[0]);
+    match responses.recv_timeout(Duration::from_secs(5)).unwrap() {
+        WorkerResponse::Details(_, details) => assert_eq!(details?.hunks().len(), 1),
+        other => panic!("Expected details, got {:?}", other),
+    }
+    assert_eq!(worker.cache_hits(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn worker_answers_log() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    for n in 0..3 {
+        dir.set_file("a", n.to_string().as_bytes());
+        dir.commit_all();
+    }
+
+    let repo = Repo::open(dir.path())?;
+    let (tx, responses) = mpsc::channel();
+    let worker = repo.worker(move |response| tx.send(response).unwrap())?;
+
+    worker.log(&LogOptions::new(), 2);
+    match responses.recv_timeout(Duration::from_secs(5)).unwrap() {
+        WorkerResponse::Log(commits) => {
+            let commits = commits?;
+            assert_eq!(commits.len(), 2);
+            assert_eq!(commits[0].id().to_string(), dir.rev_parse("HEAD").unwrap());
+        }
+        other => panic!("Expected log, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn staging_writes_the_index() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("f", b"contents");
+    let status = repo.status()?;
+    repo.stage_file(status[0].unstaged().unwrap().file())?;
+    // Read back by git itself, which only sees the index on disk
+    assert_eq!(dir.index_contents("f"), "contents");
+
+    repo.undo()?;
+    let path = dir.path_str();
+    assert!((run_cmd! {
+        cd $path;
+        git ls-files --error-unmatch f;
+    })
+    .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn corrupt_history_is_moved_aside() -> Result<()> {
+    init_logs();
+    let dir = SampleRepoDir::new();
+
+    let history = dir.path().join(".git/idgit/history.json");
+    fs::create_dir_all(history.parent().unwrap()).unwrap();
+    fs::write(&history, b"{ not json").unwrap();
+
+    let repo = Repo::open(dir.path())?;
+    assert!(!repo.can_undo());
+    assert!(!history.exists());
+    assert!(dir.path().join(".git/idgit/history.json.corrupt").exists());
+
+    // An earlier one isn't overwritten
+    fs::write(&history, b"{ also not json").unwrap();
+    Repo::open(dir.path())?;
+    assert_eq!(
+        fs::read(dir.path().join(".git/idgit/history.json.corrupt")).unwrap(),
+        b"{ not json"
+    );
+    assert_eq!(
+        fs::read(dir.path().join(".git/idgit/history.json.corrupt.1")).unwrap(),
+        b"{ also not json"
+    );
+
+    fs::write(&history, br#"{"version": 9999, "history": null}"#).unwrap();
+    let repo = Repo::open(dir.path())?;
+    assert!(!repo.can_undo());
+    assert!(dir.path().join(".git/idgit/history.json.v9999").exists());
+
+    Ok(())
+}
+
+#[test]
+fn undo_after_external_change_errors() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("file", b"committed");
+    dir.commit_all();
+    dir.set_file("file", b"unstaged");
+
+    let file = repo.status()?[0].unstaged().unwrap().file().clone();
+    repo.discard_file(&file, DiscardTo::Index)?;
+
+    dir.set_file("file", b"edited elsewhere");
+
+    let err = repo.undo().unwrap_err();
+    assert_matches!(&err, idgit::Error::HistoryDiverged(divergence) if
+        divergence.head_moved().is_none() &&
+        !divergence.index_changed() &&
+        divergence.changed_paths() == [Path::new("file")]
+    );
+    assert_eq!(
+        fs::read(dir.path().join("file")).unwrap(),
+        b"edited elsewhere"
+    );
+
+    repo.force_undo()?;
+    assert_eq!(fs::read(dir.path().join("file")).unwrap(), b"unstaged");
+
+    Ok(())
+}
+
+#[test]
+fn redo_after_external_commit_errors() -> Result<()> {
+    init_logs();
+    let mut dir = SampleRepoDir::new();
+    let mut repo = Repo::open(dir.path())?;
+
+    dir.set_file("a", b"a");
+    dir.set_file("b", b"b");
+
+    let status = repo.status()?;
+    repo.stage_file(status[0].unstaged().unwrap().file())?;
+    repo.undo()?;
+
+    dir.add("b");
+    assert_matches!(repo.redo(), Err(idgit::Error::HistoryDiverged(divergence)) if
+        divergence.index_changed()
+    );
+
+    Ok(())
+}